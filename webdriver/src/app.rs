@@ -0,0 +1,276 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+use crate::capabilities::DdgCapabilities;
+use crate::handler::Platform;
+
+/// Xcode build configuration the app was built with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum BuildConfiguration {
+    #[default]
+    Debug,
+    Release,
+    Alpha,
+}
+
+impl BuildConfiguration {
+    /// Directory under `Build/Products` that xcodebuild writes this configuration to
+    fn products_dir(&self, platform: Platform) -> String {
+        let name = match self {
+            BuildConfiguration::Debug => "Debug",
+            BuildConfiguration::Release => "Release",
+            BuildConfiguration::Alpha => "Alpha",
+        };
+        match platform {
+            Platform::IOS => format!("{}-iphonesimulator", name),
+            Platform::MacOS => name.to_string(),
+        }
+    }
+}
+
+/// App bundle a session runs
+#[derive(Debug)]
+pub struct AppBundle {
+    /// Path of the `.app` directory
+    pub path: PathBuf,
+    /// Directory a zipped `ddg:app` was extracted to, removed when the session ends
+    pub extracted_to: Option<PathBuf>,
+}
+
+impl AppBundle {
+    /// Locate the app for a session, extracting it if `ddg:app` is a zip
+    /// archive, and check that it is an app bundle for `platform`
+    pub fn resolve(platform: Platform, ddg_caps: &DdgCapabilities) -> WebDriverResult<Self> {
+        let app = match ddg_caps.app {
+            Some(ref app) => {
+                let app = PathBuf::from(app);
+                if app.is_file() && app.extension().is_some_and(|ext| ext == "zip") {
+                    let extracted_to = std::env::temp_dir().join(format!("ddgdriver-app-{}", Uuid::new_v4()));
+                    let path = extract_app(&app, &extracted_to);
+                    if path.is_err() {
+                        let _ = std::fs::remove_dir_all(&extracted_to);
+                    }
+                    AppBundle { path: path?, extracted_to: Some(extracted_to) }
+                } else {
+                    AppBundle { path: app, extracted_to: None }
+                }
+            }
+            None => AppBundle {
+                path: default_app_path(
+                    platform,
+                    ddg_caps.build_configuration,
+                    std::env::var_os("MACOS_APP_PATH").map(PathBuf::from),
+                ),
+                extracted_to: None,
+            },
+        };
+        info!("App Path: {:?}", app.path);
+        if let Err(e) = check_app_bundle(&app.path, platform) {
+            app.remove_extracted();
+            return Err(e);
+        }
+        Ok(app)
+    }
+
+    /// Remove the extracted copy of a zipped app, if any
+    pub fn remove_extracted(&self) {
+        if let Some(ref dir) = self.extracted_to {
+            info!("Removing extracted app {:?}", dir);
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// SHA-256 over every file in an app bundle and its path inside the bundle,
+/// so a rebuild that changes anything gives a different hash
+pub fn content_hash(bundle: &Path) -> std::io::Result<String> {
+    let mut files = Vec::new();
+    collect_files(bundle, &mut files)?;
+    files.sort();
+    let mut hasher = Sha256::new();
+    for file in files {
+        let relative = file.strip_prefix(bundle).unwrap_or(&file);
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        if file.is_symlink() {
+            hasher.update(std::fs::read_link(&file)?.to_string_lossy().as_bytes());
+        } else {
+            hasher.update(std::fs::read(&file)?);
+        }
+        hasher.update([0]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Where the app ends up when built locally, following `macos_app_path` (the
+/// `MACOS_APP_PATH` environment variable) and `DERIVED_DATA_PATH`. A requested
+/// build configuration wins over `macos_app_path`, which points at a single build.
+fn default_app_path(
+    platform: Platform,
+    configuration: Option<BuildConfiguration>,
+    macos_app_path: Option<PathBuf>,
+) -> PathBuf {
+    if platform == Platform::MacOS && configuration.is_none() {
+        if let Some(path) = macos_app_path {
+            return path;
+        }
+    }
+    let configuration = configuration.unwrap_or_default();
+    let derived_data_path = if let Ok(env_path) = std::env::var("DERIVED_DATA_PATH") {
+        PathBuf::from(env_path)
+    } else {
+        let current_dir = std::env::current_dir().expect("Failed to get current directory");
+        match platform {
+            Platform::IOS => current_dir.join("../DerivedData"),
+            // apple-browsers DerivedData is in the ddg-workflow monorepo
+            Platform::MacOS => current_dir.join("../ddg-workflow/apple-browsers/DerivedData"),
+        }
+    };
+    derived_data_path
+        .join("Build/Products")
+        .join(configuration.products_dir(platform))
+        .join("DuckDuckGo.app")
+}
+
+/// Unpack a zipped app into `destination` and return the `.app` inside it
+fn extract_app(archive: &Path, destination: &Path) -> WebDriverResult<PathBuf> {
+    info!("Extracting {:?} to {:?}", archive, destination);
+    let output = Command::new("ditto")
+        .args(["-x", "-k"])
+        .arg(archive)
+        .arg(destination)
+        .output()
+        .map_err(|e| session_not_created(format!("Failed to run ditto: {}", e)))?;
+    if !output.status.success() {
+        return Err(session_not_created(format!(
+            "Failed to extract {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let entries = std::fs::read_dir(destination)
+        .map_err(|e| session_not_created(format!("Failed to read {}: {}", destination.display(), e)))?;
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .find(|path| path.extension().is_some_and(|ext| ext == "app"))
+        .ok_or_else(|| session_not_created(format!("{} does not contain an .app bundle", archive.display())))
+}
+
+/// Fail early with a clear error instead of letting install or launch fail later
+fn check_app_bundle(path: &Path, platform: Platform) -> WebDriverResult<()> {
+    if !path.is_dir() || path.extension().is_none_or(|ext| ext != "app") {
+        return Err(session_not_created(format!("App bundle not found at {}", path.display())));
+    }
+    // macOS bundles keep Info.plist under Contents/, iOS bundles at the root
+    let is_macos_bundle = path.join("Contents/Info.plist").is_file();
+    let is_ios_bundle = path.join("Info.plist").is_file();
+    let matches = match platform {
+        Platform::MacOS => is_macos_bundle,
+        Platform::IOS => is_ios_bundle,
+    };
+    if !matches {
+        return Err(session_not_created(format!(
+            "{} is not {} app bundle",
+            path.display(),
+            match platform {
+                Platform::MacOS => "a macOS",
+                Platform::IOS => "an iOS simulator",
+            }
+        )));
+    }
+    Ok(())
+}
+
+/// Path of the app's Info.plist
+pub fn info_plist(app_path: &str, platform: Platform) -> String {
+    match platform {
+        Platform::MacOS => format!("{}/Contents/Info.plist", app_path),
+        Platform::IOS => format!("{}/Info.plist", app_path),
+    }
+}
+
+fn session_not_created(message: String) -> WebDriverError {
+    WebDriverError::new(ErrorStatus::SessionNotCreated, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// App bundle in a temporary directory, removed when the `TempDir` is dropped
+    fn fake_bundle(layout: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let app = dir.path().join("DuckDuckGo.app");
+        let plist = app.join(layout);
+        std::fs::create_dir_all(plist.parent().unwrap()).unwrap();
+        std::fs::write(plist, "").unwrap();
+        (dir, app)
+    }
+
+    fn resolve(platform: Platform, app: &Path) -> WebDriverResult<AppBundle> {
+        let ddg_caps = DdgCapabilities {
+            app: Some(app.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        AppBundle::resolve(platform, &ddg_caps)
+    }
+
+    #[test]
+    fn accepts_a_bundle_for_the_platform() {
+        let (_dir, app) = fake_bundle("Contents/Info.plist");
+        assert_eq!(resolve(Platform::MacOS, &app).unwrap().path, app);
+        let (_dir, app) = fake_bundle("Info.plist");
+        assert_eq!(resolve(Platform::IOS, &app).unwrap().path, app);
+    }
+
+    #[test]
+    fn missing_bundle_is_session_not_created() {
+        let err = resolve(Platform::IOS, Path::new("/nonexistent/DuckDuckGo.app")).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::SessionNotCreated);
+    }
+
+    #[test]
+    fn bundle_for_the_other_platform_is_session_not_created() {
+        let (_dir, app) = fake_bundle("Contents/Info.plist");
+        let err = resolve(Platform::IOS, &app).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::SessionNotCreated);
+        assert!(err.message.ends_with("is not an iOS simulator app bundle"));
+    }
+
+    #[test]
+    fn content_hash_follows_bundle_contents() {
+        let (_dir, app) = fake_bundle("Info.plist");
+        let before = content_hash(&app).unwrap();
+        assert_eq!(content_hash(&app).unwrap(), before);
+        std::fs::write(app.join("DuckDuckGo"), "binary").unwrap();
+        assert_ne!(content_hash(&app).unwrap(), before);
+    }
+
+    #[test]
+    fn build_configuration_selects_the_products_dir() {
+        assert_eq!(BuildConfiguration::Alpha.products_dir(Platform::IOS), "Alpha-iphonesimulator");
+        assert_eq!(BuildConfiguration::Release.products_dir(Platform::MacOS), "Release");
+        let macos_app_path = || Some(PathBuf::from("/apps/DuckDuckGo.app"));
+        assert_eq!(default_app_path(Platform::MacOS, None, macos_app_path()), PathBuf::from("/apps/DuckDuckGo.app"));
+        let release = default_app_path(Platform::MacOS, Some(BuildConfiguration::Release), macos_app_path());
+        assert!(release.ends_with("Build/Products/Release/DuckDuckGo.app"));
+        let ios = default_app_path(Platform::IOS, None, macos_app_path());
+        assert!(ios.ends_with("Build/Products/Debug-iphonesimulator/DuckDuckGo.app"));
+    }
+}
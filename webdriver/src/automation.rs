@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use webdriver::error::{ErrorStatus, WebDriverError};
+
+/// Default deadline for a single request to the automation server
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lowest automation protocol version that accepts parameters in a POST JSON body
+const JSON_BODY_PROTOCOL_VERSION: u32 = 2;
+
+/// How command parameters are sent to the automation server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestFormat {
+    /// `GET /<method>?key=value`, understood by every app build
+    Query,
+    /// `POST /<method>` with the parameters as a JSON object
+    JsonBody,
+}
+
+static REQUEST_FORMATS: OnceLock<Mutex<HashMap<u16, RequestFormat>>> = OnceLock::new();
+
+fn request_formats() -> &'static Mutex<HashMap<u16, RequestFormat>> {
+    REQUEST_FORMATS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Ask the automation server on `port` which request format it supports and
+/// remember the answer for later requests. App builds that predate the
+/// `/automationProtocol` endpoint get the query string format. If the server
+/// can't be reached the query string format is used without remembering it,
+/// so the next request asks again.
+pub fn negotiate_request_format(port: u16) -> RequestFormat {
+    let version = match make_request(port, "automationProtocol", &HashMap::new(), Some(Duration::from_secs(2)), RequestFormat::Query) {
+        Ok(message) => message.trim().parse::<u32>().ok(),
+        Err(e @ (AutomationError::Timeout { .. } | AutomationError::Connection { .. })) => {
+            warn!("Could not negotiate a request format on port {}: {}", port, e);
+            return RequestFormat::Query;
+        },
+        Err(_) => None,
+    };
+    let format = match version {
+        Some(version) if version >= JSON_BODY_PROTOCOL_VERSION => RequestFormat::JsonBody,
+        _ => RequestFormat::Query,
+    };
+    info!("Automation server on port {} speaks protocol {:?}, using {:?}", port, version, format);
+    request_formats().lock().unwrap().insert(port, format);
+    format
+}
+
+/// Forget the request format negotiated on `port`, once the app that
+/// listened there is gone and the port may go to a different build
+pub fn forget_request_format(port: u16) {
+    request_formats().lock().unwrap().remove(&port);
+}
+
+fn request_format(port: u16) -> RequestFormat {
+    let known = request_formats().lock().unwrap().get(&port).copied();
+    known.unwrap_or_else(|| negotiate_request_format(port))
+}
+
+/// Percent-encode parameters into a query string
+fn encode_query(params: &HashMap<&str, &str>) -> String {
+    params.iter()
+        .map(|(key, value)| format!("{}={}", urlencoding::encode(key), urlencoding::encode(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+/// Failure talking to the automation server embedded in the app
+#[derive(Debug)]
+pub enum AutomationError {
+    /// The request did not complete before the transport deadline
+    Timeout { method: String, timeout: Duration },
+    /// The automation server could not be reached or dropped the connection
+    Connection { method: String, message: String },
+    /// The automation server answered with something other than its JSON envelope
+    InvalidResponse { method: String, body: String },
+    /// The automation server ran the command and reported an error
+    Server { method: String, error: ErrorStatus, message: String },
+}
+
+impl fmt::Display for AutomationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AutomationError::Timeout { method, timeout } => {
+                write!(f, "Automation server request /{} timed out after {:?}", method, timeout)
+            },
+            AutomationError::Connection { method, message } => {
+                write!(f, "Automation server request /{} failed: {}", method, message)
+            },
+            AutomationError::InvalidResponse { method, body } => {
+                write!(f, "Automation server returned an invalid response for /{}: {}", method, body)
+            },
+            AutomationError::Server { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<AutomationError> for WebDriverError {
+    fn from(err: AutomationError) -> WebDriverError {
+        let message = err.to_string();
+        let status = match err {
+            AutomationError::Timeout { .. } => ErrorStatus::Timeout,
+            AutomationError::Connection { .. } | AutomationError::InvalidResponse { .. } => {
+                ErrorStatus::UnknownError
+            },
+            AutomationError::Server { error, .. } => error,
+        };
+        WebDriverError::new(status, message)
+    }
+}
+
+/// JSON envelope returned by every automation server endpoint:
+/// `{ "message": "...", "requestPath": "/...", "error": "javascript error" }`
+/// where `error` is only present when the command failed.
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Error reported when the app answers with a failure status but no `error` code
+fn default_error_status(method: &str) -> ErrorStatus {
+    match method {
+        "execute" => ErrorStatus::JavascriptError,
+        "switchToWindow" | "closeWindow" | "getWindowHandle" => ErrorStatus::NoSuchWindow,
+        _ => ErrorStatus::UnknownError,
+    }
+}
+
+/// Send `method` to the automation server listening on `port` and return the
+/// `message` field of its response. Parameter values are sent unescaped; they
+/// are encoded as needed for the request format the app negotiated. A
+/// `timeout` of `None` waits indefinitely.
+pub fn make_server_request(port: u16, method: &str, params: &HashMap<&str, &str>, timeout: Option<Duration>) -> Result<String, AutomationError> {
+    make_request(port, method, params, timeout, request_format(port))
+}
+
+fn make_request(
+    port: u16,
+    method: &str,
+    params: &HashMap<&str, &str>,
+    timeout: Option<Duration>,
+    format: RequestFormat,
+) -> Result<String, AutomationError> {
+    let transport_error = |e: reqwest::Error| {
+        match timeout {
+            Some(timeout) if e.is_timeout() => {
+                info!("Request timed out");
+                AutomationError::Timeout { method: method.to_string(), timeout }
+            },
+            _ => AutomationError::Connection { method: method.to_string(), message: e.to_string() },
+        }
+    };
+    let client = reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(transport_error)?;
+    let request = match format {
+        RequestFormat::Query => {
+            let url = format!("http://localhost:{}/{method}?{}", port, encode_query(params));
+            info!("URL to send: {:?}", url);
+            client.get(url)
+        },
+        RequestFormat::JsonBody => {
+            let url = format!("http://localhost:{}/{method}", port);
+            let body = serde_json::to_string(params).expect("Failed to serialize request parameters");
+            info!("POST to {:?} with body: {}", url, body);
+            client.post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+        },
+    };
+    let response = request.send().map_err(transport_error)?;
+    let status = response.status();
+    let body = response.text().map_err(transport_error)?;
+    info!("Response ({}): {:#?}", status, body);
+
+    let envelope: Envelope = serde_json::from_str(&body).map_err(|_| AutomationError::InvalidResponse {
+        method: method.to_string(),
+        body: body.clone(),
+    })?;
+
+    if envelope.error.is_some() || !status.is_success() {
+        let error = match envelope.error {
+            Some(code) => ErrorStatus::from(code),
+            None => default_error_status(method),
+        };
+        let message = envelope.message.unwrap_or_else(|| format!("Automation server returned {}", status));
+        return Err(AutomationError::Server { method: method.to_string(), error, message });
+    }
+
+    envelope.message.ok_or(AutomationError::InvalidResponse { method: method.to_string(), body })
+}
+
+/// Decode a JSON payload carried in the `message` field of a response
+pub fn parse_message<T: DeserializeOwned>(method: &str, message: &str) -> Result<T, AutomationError> {
+    serde_json::from_str(message).map_err(|_| AutomationError::InvalidResponse {
+        method: method.to_string(),
+        body: message.to_string(),
+    })
+}
+
+/// Element id from the response to the content lookup script, which
+/// resolves to the element's id or to `{ "notFound": true, "message": "..." }`
+/// once the implicit wait is over
+pub fn parse_element_id(message: &str) -> Result<String, AutomationError> {
+    match serde_json::from_str::<Value>(message) {
+        Ok(Value::String(id)) => Ok(id),
+        Ok(Value::Object(result)) if result.contains_key("notFound") => Err(AutomationError::Server {
+            method: "execute".to_string(),
+            error: ErrorStatus::NoSuchElement,
+            message: result
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or("No element found")
+                .to_string(),
+        }),
+        Ok(_) => Err(AutomationError::InvalidResponse { method: "execute".to_string(), body: message.to_string() }),
+        // Older app builds send the id unquoted
+        Err(_) => Ok(message.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Minimal stand-in for the app's automation server: accepts one
+    /// connection and answers it with `status` and `body` after `delay`.
+    /// A `None` status drops the connection without replying. The raw
+    /// request line and body are sent back over the returned channel.
+    fn mock_server(status: Option<u16>, body: &'static str, delay: Duration) -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut request_body = vec![0; content_length];
+            reader.read_exact(&mut request_body).unwrap();
+            let _ = sender.send(format!("{}{}", request_line, String::from_utf8(request_body).unwrap()));
+            thread::sleep(delay);
+            if let Some(status) = status {
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = reader.get_mut().write_all(response.as_bytes());
+            }
+        });
+        (port, receiver)
+    }
+
+    fn request(port: u16, method: &str) -> Result<String, AutomationError> {
+        make_request(port, method, &HashMap::new(), Some(Duration::from_millis(500)), RequestFormat::Query)
+    }
+
+    #[test]
+    fn returns_message_on_success() {
+        let (port, _) = mock_server(Some(200), r#"{"message":"https://example.com/","requestPath":"/getUrl"}"#, Duration::ZERO);
+        assert_eq!(request(port, "getUrl").unwrap(), "https://example.com/");
+    }
+
+    #[test]
+    fn slow_server_maps_to_timeout() {
+        let (port, _) = mock_server(Some(200), r#"{"message":"late"}"#, Duration::from_secs(2));
+        let err = WebDriverError::from(request(port, "navigate").unwrap_err());
+        assert_eq!(err.error, ErrorStatus::Timeout);
+    }
+
+    #[test]
+    fn dropped_connection_maps_to_unknown_error() {
+        let (port, _) = mock_server(None, "", Duration::ZERO);
+        let err = WebDriverError::from(request(port, "getUrl").unwrap_err());
+        assert_eq!(err.error, ErrorStatus::UnknownError);
+    }
+
+    #[test]
+    fn non_json_body_maps_to_unknown_error() {
+        let (port, _) = mock_server(Some(200), "<html>Internal Server Error</html>", Duration::ZERO);
+        let err = WebDriverError::from(request(port, "getUrl").unwrap_err());
+        assert_eq!(err.error, ErrorStatus::UnknownError);
+        assert!(err.message.contains("<html>Internal Server Error</html>"));
+    }
+
+    #[test]
+    fn script_error_keeps_server_message() {
+        let (port, _) = mock_server(
+            Some(500),
+            r#"{"message":"ReferenceError: foo is not defined","error":"javascript error"}"#,
+            Duration::ZERO,
+        );
+        let err = WebDriverError::from(request(port, "execute").unwrap_err());
+        assert_eq!(err.error, ErrorStatus::JavascriptError);
+        assert_eq!(err.message, "ReferenceError: foo is not defined");
+    }
+
+    #[test]
+    fn missing_element_maps_to_no_such_element() {
+        let (port, _) = mock_server(
+            Some(200),
+            r#"{"message":"{\"notFound\":true,\"message\":\"No element matches css selector #missing after 3 attempts\"}"}"#,
+            Duration::ZERO,
+        );
+        let err = WebDriverError::from(request(port, "execute").and_then(|message| parse_element_id(&message)).unwrap_err());
+        assert_eq!(err.error, ErrorStatus::NoSuchElement);
+        assert_eq!(err.message, "No element matches css selector #missing after 3 attempts");
+
+        let (port, _) = mock_server(Some(404), r#"{"message":"Element not found","error":"no such element"}"#, Duration::ZERO);
+        let err = WebDriverError::from(request(port, "execute").unwrap_err());
+        assert_eq!(err.error, ErrorStatus::NoSuchElement);
+
+        assert_eq!(parse_element_id(r#""d3b0c442-98fc-4c7f-9b6a-0b1ed1f2a7c1""#).unwrap(), "d3b0c442-98fc-4c7f-9b6a-0b1ed1f2a7c1");
+        assert_eq!(parse_element_id("d3b0c442-98fc-4c7f-9b6a-0b1ed1f2a7c1").unwrap(), "d3b0c442-98fc-4c7f-9b6a-0b1ed1f2a7c1");
+    }
+
+    #[test]
+    fn failed_window_switch_maps_to_no_such_window() {
+        let (port, _) = mock_server(Some(404), r#"{"message":"Unknown window handle abc"}"#, Duration::ZERO);
+        let err = WebDriverError::from(request(port, "switchToWindow").unwrap_err());
+        assert_eq!(err.error, ErrorStatus::NoSuchWindow);
+        assert_eq!(err.message, "Unknown window handle abc");
+    }
+
+    #[test]
+    fn query_format_encodes_keys_and_values() {
+        let (port, requests) = mock_server(Some(200), r#"{"message":"ok"}"#, Duration::ZERO);
+        let mut params = HashMap::new();
+        params.insert("a key", "x=1&y=2");
+        make_request(port, "navigate", &params, None, RequestFormat::Query).unwrap();
+        assert_eq!(requests.recv().unwrap(), "GET /navigate?a%20key=x%3D1%26y%3D2 HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn json_body_format_posts_parameters() {
+        let (port, requests) = mock_server(Some(200), r#"{"message":"ok"}"#, Duration::ZERO);
+        let script = "return 'a&b' + \"?\";".repeat(1000);
+        let mut params = HashMap::new();
+        params.insert("script", script.as_str());
+        make_request(port, "execute", &params, None, RequestFormat::JsonBody).unwrap();
+        let request = requests.recv().unwrap();
+        let (request_line, body) = request.split_once("\r\n").unwrap();
+        assert_eq!(request_line, "POST /execute HTTP/1.1");
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["script"], script);
+    }
+
+    #[test]
+    fn negotiates_json_body_with_newer_apps() {
+        let (port, _) = mock_server(Some(200), r#"{"message":"2","requestPath":"/automationProtocol"}"#, Duration::ZERO);
+        assert_eq!(negotiate_request_format(port), RequestFormat::JsonBody);
+        assert_eq!(request_format(port), RequestFormat::JsonBody);
+    }
+
+    #[test]
+    fn falls_back_to_query_for_older_apps() {
+        let (port, _) = mock_server(Some(404), "Not Found", Duration::ZERO);
+        assert_eq!(negotiate_request_format(port), RequestFormat::Query);
+        assert_eq!(request_format(port), RequestFormat::Query);
+    }
+
+    #[test]
+    fn unreachable_or_released_ports_are_not_remembered() {
+        let (port, _) = mock_server(None, "", Duration::ZERO);
+        assert_eq!(negotiate_request_format(port), RequestFormat::Query);
+        assert!(!request_formats().lock().unwrap().contains_key(&port));
+
+        let (port, _) = mock_server(Some(200), r#"{"message":"2"}"#, Duration::ZERO);
+        assert_eq!(negotiate_request_format(port), RequestFormat::JsonBody);
+        forget_request_format(port);
+        assert!(!request_formats().lock().unwrap().contains_key(&port));
+    }
+}
@@ -0,0 +1,559 @@
+use serde_json::{Map, Value};
+use url::Url;
+use webdriver::capabilities::{BrowserCapabilities, Capabilities, CapabilitiesMatching};
+use webdriver::command::{NewSessionParameters, TimeoutsParameters};
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+use crate::app::BuildConfiguration;
+use crate::feature_flags::FeatureFlags;
+use crate::handler::Platform;
+use crate::remote_config::ValidationMode;
+
+/// Prefix of the vendor capabilities the driver understands
+const VENDOR_PREFIX: &str = "ddg:";
+
+/// Simulator device type used when neither `ddg:deviceType` nor `TARGET_DEVICE` is set
+const DEFAULT_DEVICE_TYPE: &str = "iPhone-16";
+/// Simulator runtime used when neither `ddg:osRuntime` nor `TARGET_OS` is set
+const DEFAULT_OS_RUNTIME: &str = "iOS-18-2";
+
+/// What the driver can offer, checked against each merged firstMatch entry
+pub struct DuckDuckGoCapabilities {
+    /// Platform used when the client does not ask for one
+    default_platform: Platform,
+}
+
+impl DuckDuckGoCapabilities {
+    pub fn new(default_platform: Platform) -> Self {
+        DuckDuckGoCapabilities { default_platform }
+    }
+
+    /// Platform a matched set of capabilities asks for
+    pub fn platform(&self, capabilities: &Capabilities) -> Platform {
+        capabilities
+            .get("platformName")
+            .and_then(|name| name.as_str())
+            .and_then(Platform::from_name)
+            .unwrap_or(self.default_platform)
+    }
+
+    /// Merge `alwaysMatch` with each `firstMatch` entry and pick the first
+    /// entry the driver can satisfy
+    pub fn match_session(&mut self, params: &NewSessionParameters) -> WebDriverResult<Capabilities> {
+        let capabilities = params.match_browser(self)?.ok_or_else(|| {
+            WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                "Unable to find a matching set of capabilities",
+            )
+        })?;
+        // Navigation always waits for the page to load
+        match capabilities.get("pageLoadStrategy").and_then(|strategy| strategy.as_str()) {
+            None | Some("normal") => Ok(capabilities),
+            Some(strategy) => Err(WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Unsupported pageLoadStrategy {}, only normal is supported", strategy),
+            )),
+        }
+    }
+}
+
+impl BrowserCapabilities for DuckDuckGoCapabilities {
+    fn init(&mut self, _: &Capabilities) {}
+
+    fn browser_name(&mut self, _: &Capabilities) -> WebDriverResult<Option<String>> {
+        Ok(Some("duckduckgo".to_string()))
+    }
+
+    /// The app bundle is not known before the session starts, so a requested
+    /// browserVersion can never be matched
+    fn browser_version(&mut self, _: &Capabilities) -> WebDriverResult<Option<String>> {
+        Ok(None)
+    }
+
+    fn compare_browser_version(&mut self, version: &str, comparison: &str) -> WebDriverResult<bool> {
+        Ok(version == comparison)
+    }
+
+    /// Both platforms can be driven, so any name we recognise matches as requested
+    fn platform_name(&mut self, capabilities: &Capabilities) -> WebDriverResult<Option<String>> {
+        let requested = capabilities.get("platformName").and_then(|name| name.as_str());
+        match requested {
+            Some(name) if Platform::from_name(name).is_some() => Ok(Some(name.to_string())),
+            _ => Ok(Some(self.default_platform.name().to_string())),
+        }
+    }
+
+    /// Simulators get the test CA certificate installed, so the test servers'
+    /// certificates are trusted
+    fn accept_insecure_certs(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(true)
+    }
+
+    fn set_window_rect(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn strict_file_interactability(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn web_socket_url(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn webauthn_virtual_authenticators(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn webauthn_extension_uvm(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn webauthn_extension_prf(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn webauthn_extension_large_blob(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn webauthn_extension_cred_blob(&mut self, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    fn accept_proxy(&mut self, _: &Map<String, Value>, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(false)
+    }
+
+    /// Type check `ddg:*` capabilities; other vendors' capabilities are ignored
+    fn validate_custom(&mut self, name: &str, value: &Value) -> WebDriverResult<()> {
+        if !name.starts_with(VENDOR_PREFIX) {
+            return Ok(());
+        }
+        let mut single = Map::new();
+        single.insert(name.to_string(), value.clone());
+        DdgCapabilities::parse(&single).map(|_| ())
+    }
+
+    fn accept_custom(&mut self, _: &str, _: &Value, _: &Capabilities) -> WebDriverResult<bool> {
+        Ok(true)
+    }
+}
+
+/// DuckDuckGo-specific session capabilities
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DdgCapabilities {
+    /// Custom URL for privacy configuration (overrides bundled config via cache write)
+    #[serde(rename = "ddg:privacyConfigURL")]
+    pub privacy_config_url: Option<String>,
+    /// Privacy configuration object, installed through the same cache write as a fetched one
+    #[serde(rename = "ddg:privacyConfig")]
+    pub privacy_config: Option<Value>,
+    /// JSON Merge Patch object or JSON Patch operation list applied to the
+    /// config from `ddg:privacyConfigURL` or `ddg:privacyConfig`
+    #[serde(rename = "ddg:privacyConfigPatch")]
+    pub privacy_config_patch: Option<Value>,
+    /// Whether a privacy config, tracker data set or surrogates file that
+    /// fails to fetch or has the wrong shape fails NewSession (`strict`, the default) or is installed anyway (`lenient`)
+    #[serde(rename = "ddg:privacyConfigValidation")]
+    pub privacy_config_validation: Option<ValidationMode>,
+    /// Local file path for privacy configuration (uses TEST_PRIVACY_CONFIG_PATH env var)
+    #[serde(rename = "ddg:privacyConfigPath")]
+    pub privacy_config_path: Option<String>,
+    /// Custom URL for the tracker data set, installed like the privacy config
+    #[serde(rename = "ddg:trackerDataURL")]
+    pub tracker_data_url: Option<String>,
+    /// Custom URL for the surrogate scripts, installed like the privacy config
+    #[serde(rename = "ddg:surrogatesURL")]
+    pub surrogates_url: Option<String>,
+    /// Simulator device type for iOS sessions, e.g. `iPhone-16`
+    #[serde(rename = "ddg:deviceType")]
+    pub device_type: Option<String>,
+    /// Simulator runtime for iOS sessions, e.g. `iOS-18-2`
+    #[serde(rename = "ddg:osRuntime")]
+    pub os_runtime: Option<String>,
+    /// `.app` bundle or zipped archive of one to run instead of the local build
+    #[serde(rename = "ddg:app")]
+    pub app: Option<String>,
+    /// Build configuration of the local build to run when `ddg:app` is not set
+    #[serde(rename = "ddg:buildConfiguration")]
+    pub build_configuration: Option<BuildConfiguration>,
+    /// Keep the app running after the session and attach to it from the next
+    /// one, reinstalling only when the bundle changes
+    #[serde(rename = "ddg:reuseApp")]
+    pub reuse_app: Option<bool>,
+    /// Local feature flag overrides, by flag name
+    #[serde(rename = "ddg:featureFlags")]
+    pub feature_flags: Option<FeatureFlags>,
+}
+
+impl DdgCapabilities {
+    /// Read the `ddg:*` entries of a merged capabilities object
+    pub fn from_capabilities(capabilities: &Capabilities) -> WebDriverResult<Self> {
+        let caps = Self::parse(capabilities)?;
+        caps.validate_combination()?;
+        info!("Parsed DuckDuckGo capabilities: {:?}", caps);
+        Ok(caps)
+    }
+
+    /// Read the body of a mid-session privacy config change, which takes the
+    /// same `ddg:privacyConfig*` entries as NewSession
+    pub fn privacy_config_change(body: &Capabilities) -> WebDriverResult<Self> {
+        let caps = Self::from_capabilities(body)?;
+        let privacy_only = DdgCapabilities {
+            privacy_config_url: caps.privacy_config_url.clone(),
+            privacy_config: caps.privacy_config.clone(),
+            privacy_config_patch: caps.privacy_config_patch.clone(),
+            privacy_config_validation: caps.privacy_config_validation,
+            ..Default::default()
+        };
+        if caps != privacy_only {
+            return Err(invalid_argument(
+                "Only ddg:privacyConfig, ddg:privacyConfigURL, ddg:privacyConfigPatch and ddg:privacyConfigValidation can be changed mid-session",
+            ));
+        }
+        if caps.privacy_config.is_none() && caps.privacy_config_url.is_none() {
+            return Err(invalid_argument("A privacy config change needs ddg:privacyConfig or ddg:privacyConfigURL"));
+        }
+        Ok(caps)
+    }
+
+    /// Read the body of a mid-session feature flag change, which takes a
+    /// `ddg:featureFlags` entry like NewSession
+    pub fn feature_flags_change(body: &Capabilities) -> WebDriverResult<FeatureFlags> {
+        let caps = Self::from_capabilities(body)?;
+        let flags = caps.feature_flags.clone();
+        if caps != (DdgCapabilities { feature_flags: flags.clone(), ..Default::default() }) {
+            return Err(invalid_argument("Only ddg:featureFlags can be changed through this command"));
+        }
+        match flags {
+            Some(flags) if !flags.is_empty() => Ok(flags),
+            _ => Err(invalid_argument("A feature flag change needs at least one flag in ddg:featureFlags")),
+        }
+    }
+
+    /// Type check the `ddg:*` entries one by one, before they are merged
+    fn parse(capabilities: &Capabilities) -> WebDriverResult<Self> {
+        let vendor = capabilities
+            .iter()
+            .filter(|(key, _)| key.starts_with(VENDOR_PREFIX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Map<String, Value>>();
+        let caps: DdgCapabilities = serde_json::from_value(Value::Object(vendor))
+            .map_err(|e| invalid_argument(format!("Invalid DuckDuckGo capability: {}", e)))?;
+        caps.validate()?;
+        Ok(caps)
+    }
+
+    fn validate(&self) -> WebDriverResult<()> {
+        for (name, value) in [
+            ("ddg:privacyConfigURL", &self.privacy_config_url),
+            ("ddg:trackerDataURL", &self.tracker_data_url),
+            ("ddg:surrogatesURL", &self.surrogates_url),
+        ] {
+            let Some(url) = value else {
+                continue;
+            };
+            match Url::parse(url) {
+                Ok(parsed) if ["http", "https", "file"].contains(&parsed.scheme()) => {}
+                _ => {
+                    return Err(invalid_argument(format!(
+                        "{} must be an http(s) or file URL: {}",
+                        name, url
+                    )))
+                }
+            }
+        }
+        if let Some(ref config) = self.privacy_config {
+            if !config.is_object() {
+                return Err(invalid_argument("ddg:privacyConfig must be a JSON object"));
+            }
+        }
+        if let Some(ref patch) = self.privacy_config_patch {
+            match patch {
+                Value::Object(_) => {}
+                Value::Array(_) => {
+                    serde_json::from_value::<json_patch::Patch>(patch.clone())
+                        .map_err(|e| invalid_argument(format!("Invalid ddg:privacyConfigPatch: {}", e)))?;
+                }
+                _ => return Err(invalid_argument("ddg:privacyConfigPatch must be a JSON object or an array of operations")),
+            }
+        }
+        if let Some(ref flags) = self.feature_flags {
+            if flags.keys().any(|name| name.is_empty()) {
+                return Err(invalid_argument("ddg:featureFlags names must not be empty"));
+            }
+        }
+        for (name, value) in [
+            ("ddg:privacyConfigPath", &self.privacy_config_path),
+            ("ddg:deviceType", &self.device_type),
+            ("ddg:osRuntime", &self.os_runtime),
+            ("ddg:app", &self.app),
+        ] {
+            if value.as_deref() == Some("") {
+                return Err(invalid_argument(format!("{} must not be empty", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks across capabilities, which may come from different `firstMatch`
+    /// entries and so can only run once they are merged
+    fn validate_combination(&self) -> WebDriverResult<()> {
+        if self.privacy_config.is_some() && self.privacy_config_url.is_some() {
+            return Err(invalid_argument("ddg:privacyConfig and ddg:privacyConfigURL can't both be set"));
+        }
+        if self.privacy_config_patch.is_some() && self.privacy_config.is_none() && self.privacy_config_url.is_none() {
+            return Err(invalid_argument("ddg:privacyConfigPatch needs ddg:privacyConfigURL or ddg:privacyConfig to patch"));
+        }
+        Ok(())
+    }
+
+    /// Simulator device type, falling back to `TARGET_DEVICE`
+    pub fn device_type(&self) -> String {
+        self.device_type
+            .clone()
+            .or_else(|| std::env::var("TARGET_DEVICE").ok())
+            .unwrap_or_else(|| DEFAULT_DEVICE_TYPE.to_string())
+    }
+
+    /// Simulator runtime, falling back to `TARGET_OS`
+    pub fn os_runtime(&self) -> String {
+        self.os_runtime
+            .clone()
+            .or_else(|| std::env::var("TARGET_OS").ok())
+            .unwrap_or_else(|| DEFAULT_OS_RUNTIME.to_string())
+    }
+}
+
+/// Session timeouts requested through the `timeouts` capability
+pub fn requested_timeouts(capabilities: &Capabilities) -> WebDriverResult<Option<TimeoutsParameters>> {
+    match capabilities.get("timeouts") {
+        Some(timeouts) => Ok(Some(serde_json::from_value(timeouts.clone())?)),
+        None => Ok(None),
+    }
+}
+
+fn invalid_argument<S: Into<String>>(message: S) -> WebDriverError {
+    WebDriverError::new(ErrorStatus::InvalidArgument, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn match_session(capabilities: Value) -> WebDriverResult<Capabilities> {
+        let params: NewSessionParameters =
+            serde_json::from_value(json!({ "capabilities": capabilities })).unwrap();
+        DuckDuckGoCapabilities::new(Platform::IOS).match_session(&params)
+    }
+
+    #[test]
+    fn merges_always_match_with_first_match() {
+        let merged = match_session(json!({
+            "alwaysMatch": { "browserName": "duckduckgo" },
+            "firstMatch": [{ "ddg:privacyConfigURL": "https://example.com/config.json" }],
+        }))
+        .unwrap();
+        let caps = DdgCapabilities::from_capabilities(&merged).unwrap();
+        assert_eq!(caps.privacy_config_url.as_deref(), Some("https://example.com/config.json"));
+        assert_eq!(merged["browserName"], "duckduckgo");
+    }
+
+    #[test]
+    fn picks_the_first_satisfiable_first_match_entry() {
+        let merged = match_session(json!({
+            "firstMatch": [
+                { "browserName": "firefox" },
+                { "browserName": "duckduckgo", "ddg:privacyConfigPath": "/tmp/config.json" },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(merged["ddg:privacyConfigPath"], "/tmp/config.json");
+    }
+
+    #[test]
+    fn unsatisfiable_first_match_is_session_not_created() {
+        let err = match_session(json!({
+            "firstMatch": [{ "browserName": "firefox" }, { "platformName": "windows" }],
+        }))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::SessionNotCreated);
+    }
+
+    #[test]
+    fn malformed_vendor_value_is_invalid_argument() {
+        let err = match_session(json!({
+            "alwaysMatch": { "ddg:privacyConfigURL": 42 },
+        }))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn unknown_vendor_key_is_invalid_argument() {
+        let err = match_session(json!({
+            "alwaysMatch": { "ddg:privacyConfigUrl": "https://example.com/config.json" },
+        }))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn unsupported_config_url_scheme_is_invalid_argument() {
+        let err = match_session(json!({
+            "alwaysMatch": { "ddg:privacyConfigURL": "ftp://example.com/config.json" },
+        }))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let err = match_session(json!({
+            "alwaysMatch": { "ddg:trackerDataURL": "tds.json" },
+        }))
+        .unwrap_err();
+        assert_eq!(err.message, "ddg:trackerDataURL must be an http(s) or file URL: tds.json");
+    }
+
+    #[test]
+    fn inline_privacy_config_must_be_an_object() {
+        let caps = match_session(json!({
+            "alwaysMatch": { "ddg:privacyConfig": { "features": {} } },
+        }))
+        .unwrap();
+        let caps = DdgCapabilities::from_capabilities(&caps).unwrap();
+        assert_eq!(caps.privacy_config, Some(json!({ "features": {} })));
+        let err = match_session(json!({ "alwaysMatch": { "ddg:privacyConfig": "{}" } })).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        // Combinations are only checked once the capabilities are merged
+        let caps = match_session(json!({
+            "alwaysMatch": { "ddg:privacyConfig": {}, "ddg:privacyConfigURL": "https://example.com/config.json" },
+        }))
+        .unwrap();
+        let err = DdgCapabilities::from_capabilities(&caps).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn privacy_config_patch_needs_a_valid_shape_and_a_base() {
+        let err = match_session(json!({ "alwaysMatch": { "ddg:privacyConfigPatch": 42 } })).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let err = match_session(json!({ "alwaysMatch": { "ddg:privacyConfigPatch": [{ "op": "jump" }] } })).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let caps = match_session(json!({ "alwaysMatch": { "ddg:privacyConfigPatch": { "version": 2 } } })).unwrap();
+        let err = DdgCapabilities::from_capabilities(&caps).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn privacy_config_changes_take_only_privacy_config_entries() {
+        let body = |value: Value| value.as_object().unwrap().clone();
+        let caps = DdgCapabilities::privacy_config_change(&body(json!({
+            "ddg:privacyConfigURL": "https://example.com/config.json",
+            "ddg:privacyConfigValidation": "lenient",
+        })))
+        .unwrap();
+        assert_eq!(caps.privacy_config_validation, Some(ValidationMode::Lenient));
+        let err = DdgCapabilities::privacy_config_change(&body(json!({
+            "ddg:privacyConfig": { "features": {} },
+            "ddg:reuseApp": true,
+        })))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let err = DdgCapabilities::privacy_config_change(&body(json!({
+            "ddg:privacyConfigValidation": "strict",
+        })))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn feature_flags_map_names_to_booleans() {
+        let caps = match_session(json!({
+            "alwaysMatch": { "ddg:featureFlags": { "contentScopeExperiments": true, "history": false } },
+        }))
+        .unwrap();
+        let flags = DdgCapabilities::from_capabilities(&caps).unwrap().feature_flags.unwrap();
+        assert_eq!(flags.get("contentScopeExperiments"), Some(&true));
+        assert_eq!(flags.get("history"), Some(&false));
+        let err = match_session(json!({ "alwaysMatch": { "ddg:featureFlags": { "history": "off" } } })).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let err = match_session(json!({ "alwaysMatch": { "ddg:featureFlags": { "": true } } })).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn feature_flag_changes_take_only_feature_flags() {
+        let body = |value: Value| value.as_object().unwrap().clone();
+        let flags = DdgCapabilities::feature_flags_change(&body(json!({ "ddg:featureFlags": { "history": true } }))).unwrap();
+        assert_eq!(flags.get("history"), Some(&true));
+        for invalid in [
+            json!({ "ddg:featureFlags": { "history": true }, "ddg:reuseApp": true }),
+            json!({ "ddg:featureFlags": {} }),
+            json!({}),
+        ] {
+            let err = DdgCapabilities::feature_flags_change(&body(invalid)).unwrap_err();
+            assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn only_the_normal_page_load_strategy_is_supported() {
+        assert!(match_session(json!({ "alwaysMatch": { "pageLoadStrategy": "normal" } })).is_ok());
+        for strategy in ["eager", "none"] {
+            let err = match_session(json!({ "alwaysMatch": { "pageLoadStrategy": strategy } })).unwrap_err();
+            assert_eq!(err.error, ErrorStatus::SessionNotCreated);
+        }
+    }
+
+    #[test]
+    fn other_vendor_capabilities_are_ignored() {
+        let merged = match_session(json!({
+            "alwaysMatch": { "goog:chromeOptions": { "args": [] } },
+        }))
+        .unwrap();
+        assert_eq!(DdgCapabilities::from_capabilities(&merged).unwrap(), DdgCapabilities::default());
+    }
+
+    #[test]
+    fn platform_name_selects_the_platform() {
+        let merged = match_session(json!({
+            "firstMatch": [{ "platformName": "windows" }, { "platformName": "mac" }],
+        }))
+        .unwrap();
+        let browser = DuckDuckGoCapabilities::new(Platform::IOS);
+        assert_eq!(browser.platform(&merged), Platform::MacOS);
+        assert_eq!(browser.platform(&Capabilities::new()), Platform::IOS);
+    }
+
+    #[test]
+    fn device_and_runtime_come_from_capabilities() {
+        let merged = match_session(json!({
+            "alwaysMatch": { "ddg:deviceType": "iPad-Air-11-inch-M2", "ddg:osRuntime": "iOS-17-5" },
+        }))
+        .unwrap();
+        let caps = DdgCapabilities::from_capabilities(&merged).unwrap();
+        assert_eq!(caps.device_type(), "iPad-Air-11-inch-M2");
+        assert_eq!(caps.os_runtime(), "iOS-17-5");
+    }
+
+    #[test]
+    fn unknown_build_configuration_is_invalid_argument() {
+        let err = match_session(json!({
+            "alwaysMatch": { "ddg:buildConfiguration": "Beta" },
+        }))
+        .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn reads_requested_timeouts() {
+        let merged = match_session(json!({
+            "alwaysMatch": { "timeouts": { "implicit": 500, "script": null } },
+        }))
+        .unwrap();
+        let timeouts = requested_timeouts(&merged).unwrap().unwrap();
+        assert_eq!(timeouts.implicit, Some(500));
+        assert_eq!(timeouts.script, Some(None));
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Remote configs fetched over HTTP, stored on disk by content hash. Later
+/// fetches of the same URL revalidate the cached copy with `If-None-Match`,
+/// and in offline mode only the cache is used.
+pub struct ConfigCache {
+    dir: PathBuf,
+    /// Never fetch configs, only serve them from the cache
+    offline: bool,
+}
+
+/// What the cache knows about a config URL
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedUrl {
+    /// ETag the server sent with the cached copy
+    etag: Option<String>,
+    /// Content hash of the cached copy
+    hash: String,
+}
+
+impl ConfigCache {
+    pub fn new(dir: PathBuf, offline: bool) -> Self {
+        ConfigCache { dir, offline }
+    }
+
+    /// Cache in `CONFIG_CACHE_DIR`, or `~/Library/Caches/ddgdriver/remote-configs`
+    pub fn from_env(offline: bool) -> Self {
+        let dir = match (std::env::var("CONFIG_CACHE_DIR"), std::env::var("HOME")) {
+            (Ok(dir), _) => PathBuf::from(dir),
+            (_, Ok(home)) => PathBuf::from(home).join("Library/Caches/ddgdriver/remote-configs"),
+            _ => std::env::temp_dir().join("ddgdriver-remote-configs"),
+        };
+        info!("Remote config cache: {:?}{}", dir, if offline { " (offline)" } else { "" });
+        ConfigCache::new(dir, offline)
+    }
+
+    /// Fetch a config (supports http://, https://, and file://). Local files
+    /// are read as they are; URLs go through the cache.
+    pub fn fetch(&self, config_url: &str) -> Result<Vec<u8>, String> {
+        if let Some(file_path) = config_url.strip_prefix("file://") {
+            let data = fs::read(file_path).map_err(|e| format!("Failed to read file {}: {}", file_path, e))?;
+            info!("Read {} bytes from local file", data.len());
+            return Ok(data);
+        }
+
+        let cached = self.lookup(config_url);
+        if self.offline {
+            let (_, data) = cached.ok_or_else(|| format!("{} is not in the config cache and the driver is offline", config_url))?;
+            info!("Using cached copy of {} ({} bytes, offline)", config_url, data.len());
+            return Ok(data);
+        }
+
+        let client = reqwest::blocking::Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
+        let mut request = client.get(config_url);
+        if let Some((CachedUrl { etag: Some(ref etag), .. }, _)) = cached {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().map_err(|e| format!("Failed to fetch config: {}", e))?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some((entry, data)) = cached {
+                info!("{} not modified, using cached copy {}", config_url, entry.hash);
+                return Ok(data);
+            }
+        }
+        if !response.status().is_success() {
+            return Err(format!("HTTP error: {}", response.status()));
+        }
+
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let data = response.bytes()
+            .map_err(|e| format!("Failed to read response body: {}", e))?
+            .to_vec();
+        info!("Fetched {} bytes from URL", data.len());
+        if let Err(e) = self.store(config_url, etag, &data) {
+            warn!("Failed to cache {}: {}", config_url, e);
+        }
+        Ok(data)
+    }
+
+    /// Cached entry and contents for a URL, if both are present and intact
+    fn lookup(&self, config_url: &str) -> Option<(CachedUrl, Vec<u8>)> {
+        let entry: CachedUrl = serde_json::from_slice(&fs::read(self.url_path(config_url)).ok()?).ok()?;
+        let data = fs::read(self.object_path(&entry.hash)).ok()?;
+        if content_hash(&data) != entry.hash {
+            warn!("Cached copy of {} does not match its hash, ignoring it", config_url);
+            return None;
+        }
+        Some((entry, data))
+    }
+
+    fn store(&self, config_url: &str, etag: Option<String>, data: &[u8]) -> io::Result<()> {
+        let hash = content_hash(data);
+        let object = self.object_path(&hash);
+        if !object.exists() {
+            write_atomically(&object, data)?;
+        }
+        let entry = serde_json::to_vec(&CachedUrl { etag, hash }).expect("Failed to serialize cache entry");
+        write_atomically(&self.url_path(config_url), &entry)
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("objects").join(hash)
+    }
+
+    fn url_path(&self, config_url: &str) -> PathBuf {
+        self.dir.join("urls").join(format!("{}.json", content_hash(config_url.as_bytes())))
+    }
+}
+
+/// Write through a temporary file, so concurrent sessions never read a partial file
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let dir = path.parent().expect("Cache files live in a directory");
+    fs::create_dir_all(dir)?;
+    let temp = dir.join(format!(".{}", Uuid::new_v4()));
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path)
+}
+
+/// SHA-256 of a config, hex encoded
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// ETag to store next to an installed config. It only depends on the
+/// content, so installing an identical config again doesn't make the app
+/// recompile its rules.
+pub fn content_etag(data: &[u8]) -> String {
+    format!("\"{}\"", content_hash(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    /// Config server answering one request per response in `responses`
+    /// (status, extra headers, body). The request headers are sent back
+    /// over the returned channel.
+    fn mock_server(responses: Vec<(u16, &'static str, &'static str)>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/config.json", listener.local_addr().unwrap().port());
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for (status, headers, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    request.push_str(&line.to_lowercase());
+                }
+                let _ = sender.send(request);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+                let _ = reader.get_mut().write_all(response.as_bytes());
+            }
+        });
+        (url, receiver)
+    }
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ddgdriver-config-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn revalidates_cached_configs() {
+        let dir = cache_dir("revalidate");
+        let (url, requests) = mock_server(vec![
+            (200, "ETag: \"v1\"\r\n", r#"{"version":1}"#),
+            (304, "", ""),
+        ]);
+        let cache = ConfigCache::new(dir.clone(), false);
+        assert_eq!(cache.fetch(&url).unwrap(), br#"{"version":1}"#);
+        assert!(!requests.recv().unwrap().contains("if-none-match"));
+        assert_eq!(cache.fetch(&url).unwrap(), br#"{"version":1}"#);
+        assert!(requests.recv().unwrap().contains("if-none-match: \"v1\""));
+
+        // The server is gone; offline mode only needs the cache
+        let offline = ConfigCache::new(dir.clone(), true);
+        assert_eq!(offline.fetch(&url).unwrap(), br#"{"version":1}"#);
+        assert!(offline.fetch("https://example.com/other.json").unwrap_err().contains("offline"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_tampered_cache_entries() {
+        let dir = cache_dir("tampered");
+        let cache = ConfigCache::new(dir.clone(), true);
+        cache.store("https://example.com/config.json", None, b"{}").unwrap();
+        assert!(cache.fetch("https://example.com/config.json").is_ok());
+        fs::write(cache.object_path(&content_hash(b"{}")), b"[]").unwrap();
+        assert!(cache.fetch("https://example.com/config.json").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn etags_follow_the_content() {
+        assert_eq!(content_etag(b"{}"), content_etag(b"{}"));
+        assert_ne!(content_etag(b"{}"), content_etag(b"[]"));
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{Map, Value};
+use webdriver::error::WebDriverResult;
+
+use crate::automation::{parse_message, DEFAULT_REQUEST_TIMEOUT};
+use crate::session::DriverSession;
+
+/// Feature flag names mapped to the value the app should use locally
+pub type FeatureFlags = BTreeMap<String, bool>;
+
+/// User defaults key the app reads a flag's local override from, e.g.
+/// `localOverrideContentScopeExperiments` for `contentScopeExperiments`
+pub fn override_key(flag: &str) -> String {
+    let mut chars = flag.chars();
+    match chars.next() {
+        Some(first) => format!("localOverride{}{}", first.to_uppercase(), chars.as_str()),
+        None => "localOverride".to_string(),
+    }
+}
+
+/// Set local overrides in the running app, returning the values the app
+/// resolved them to
+pub fn set_overrides(session: &DriverSession, flags: &FeatureFlags) -> WebDriverResult<FeatureFlags> {
+    let encoded = serde_json::to_string(flags).expect("Failed to serialize feature flags");
+    let response = session.request(
+        "setFeatureFlagOverrides",
+        &HashMap::from([("flags", encoded.as_str())]),
+        Some(DEFAULT_REQUEST_TIMEOUT),
+    )?;
+    Ok(parse_message("setFeatureFlagOverrides", &response)?)
+}
+
+/// Values the app resolved the requested flags to. A flag the app doesn't
+/// report, or every flag if it can't report them at all, is `null`.
+pub fn resolved(session: &DriverSession, flags: &FeatureFlags) -> Value {
+    let names = serde_json::to_string(&flags.keys().collect::<Vec<_>>()).expect("Failed to serialize flag names");
+    let reported = session
+        .request("getFeatureFlags", &HashMap::from([("names", names.as_str())]), Some(DEFAULT_REQUEST_TIMEOUT))
+        .and_then(|response| Ok(parse_message::<FeatureFlags>("getFeatureFlags", &response)?));
+    let reported = match reported {
+        Ok(reported) => reported,
+        Err(e) => {
+            warn!("App on port {} did not report its feature flags: {}", session.port, e);
+            FeatureFlags::new()
+        }
+    };
+    Value::Object(
+        flags
+            .keys()
+            .map(|name| (name.clone(), reported.get(name).map_or(Value::Null, |&enabled| enabled.into())))
+            .collect::<Map<String, Value>>(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn override_keys_capitalize_the_flag_name() {
+        assert_eq!(override_key("contentScopeExperiments"), "localOverrideContentScopeExperiments");
+        assert_eq!(override_key("History"), "localOverrideHistory");
+    }
+}
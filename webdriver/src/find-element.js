@@ -31,12 +31,15 @@ function selectElement(using, selector) {
 
 function runScript() {
     return new Promise((resolve, reject) => {
+        // implicitWait is the session's implicit wait timeout in ms, prepended by the driver
+        const deadline = Date.now() + implicitWait;
         let attempts = 0;
         function findElement() {
             const element = selectElement(using, value);
-            if (element !== null || attempts >= 5) {
+            if (element !== null || Date.now() >= deadline) {
                 if (element === null) {
-                    reject(new Error('Element not found after 5 attempts'));
+                    // Resolved rather than rejected, so the driver can report `no such element`
+                    resolve({ notFound: true, message: 'No element matches ' + using + ' ' + value + ' after ' + (attempts + 1) + ' attempts' });
                     return;
                 }
                 if (!window.__webdriver_script_results) {
@@ -54,7 +57,7 @@ function runScript() {
                 return;
             }
             attempts++;
-            const delay = Math.min(10 * Math.pow(2, attempts), 16000);
+            const delay = Math.min(10 * Math.pow(2, attempts), 16000, Math.max(deadline - Date.now(), 0));
             setTimeout(findElement, delay);
         }
         findElement();
@@ -46,6 +46,8 @@ function selectElements(using, selector) {
 
 function runScript() {
     return new Promise((resolve, reject) => {
+        // implicitWait is the session's implicit wait timeout in ms, prepended by the driver
+        const deadline = Date.now() + implicitWait;
         let attempts = 0;
         function findElements() {
             try {
@@ -66,8 +68,9 @@ function runScript() {
                 console.log('FindElements: document.body exists=' + (document.body !== null));
                 const elements = selectElements(using, value);
                 console.log('FindElements: found ' + elements.length + ' elements');
-                if (elements.length > 0 || attempts >= 5) {
-                    if (elements.length === 0 && attempts >= 5) {
+                const timedOut = Date.now() >= deadline;
+                if (elements.length > 0 || timedOut) {
+                    if (elements.length === 0) {
                         // Return debug info if no elements found after retries
                         // Try direct querySelectorAll to see if selector works
                         let directResult = 0;
@@ -79,7 +82,7 @@ function runScript() {
                             directResult = 'error: ' + e.message;
                         }
                         const debugInfo = {
-                            error: 'No elements found after 5 attempts',
+                            error: 'No elements found after ' + (attempts + 1) + ' attempts',
                             using: typeof using === 'undefined' ? 'undefined' : using,
                             value: typeof value === 'undefined' ? 'undefined' : value,
                             readyState: document.readyState,
@@ -115,7 +118,7 @@ function runScript() {
                     return;
                 }
                 attempts++;
-                const delay = Math.min(10 * Math.pow(2, attempts), 16000);
+                const delay = Math.min(10 * Math.pow(2, attempts), 16000, Math.max(deadline - Date.now(), 0));
                 setTimeout(findElements, delay);
             } catch (error) {
                 reject(new Error('Error in findElements: ' + error.message + '. Stack: ' + (error.stack || 'no stack')));
@@ -1,35 +1,36 @@
 use std::process::{Command, Stdio};
-use urlencoding;
-use webdriver::server::{Session, WebDriverHandler};
 use webdriver::httpapi::WebDriverExtensionRoute;
 use webdriver::Parameters;
 use webdriver::command::{WebDriverCommand, WebDriverExtensionCommand, WebDriverMessage};
-use webdriver::error::WebDriverResult;
-use webdriver::server::SessionTeardownKind;
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+use crate::app::{content_hash, info_plist, AppBundle};
+use crate::capabilities::{requested_timeouts, DdgCapabilities, DuckDuckGoCapabilities};
+use crate::config_cache::{content_etag, ConfigCache};
+use crate::feature_flags::{self, FeatureFlags};
+use crate::pool::SimulatorPool;
+use crate::remote_config::{ConfigKind, InstallMode, InstalledConfig, RemoteConfig};
+use crate::native_ui;
+use crate::reuse::{clear_browsing_data, reset_app_state, KeptApp};
+use crate::session::{DriverSession, Timeouts};
+use crate::tracker_report;
+use crate::automation::{
+    forget_request_format, negotiate_request_format, parse_element_id, parse_message, DEFAULT_REQUEST_TIMEOUT,
+};
 use serde_json::{Map, Value};
 use webdriver::command::WebDriverCommand::{
-    AcceptAlert, AddCookie, CloseWindow, DeleteCookie, DeleteCookies, DeleteSession, DismissAlert,
-    ElementClear, ElementClick, ElementSendKeys, ExecuteAsyncScript, ExecuteScript, Extension,
-    FindElement, FindElementElement, FindElementElements, FindElements, FindShadowRootElement,
-    FindShadowRootElements, FullscreenWindow, Get, GetActiveElement, GetAlertText, GetCSSValue,
-    GetComputedLabel, GetComputedRole, GetCookies, GetCurrentUrl, GetElementAttribute,
-    GetElementProperty, GetElementRect, GetElementTagName, GetElementText, GetNamedCookie,
-    GetPageSource, GetShadowRoot, GetTimeouts, GetTitle, GetWindowHandle, GetWindowHandles,
-    GetWindowRect, GoBack, GoForward, IsDisplayed, IsEnabled, IsSelected, MaximizeWindow,
-    MinimizeWindow, NewSession, NewWindow, PerformActions, Print, Refresh, ReleaseActions,
-    SendAlertText, SetPermission, SetTimeouts, SetWindowRect, Status, SwitchToFrame,
-    SwitchToParentFrame, SwitchToWindow, TakeElementScreenshot, TakeScreenshot,
-    WebAuthnAddCredential, WebAuthnAddVirtualAuthenticator, WebAuthnGetCredentials,
-    WebAuthnRemoveAllCredentials, WebAuthnRemoveCredential, WebAuthnRemoveVirtualAuthenticator,
-    WebAuthnSetUserVerified,
+    CloseWindow, DeleteSession, ElementClear, ElementClick, ElementSendKeys, ExecuteAsyncScript,
+    ExecuteScript, FindElement, FindElements, Get, GetCurrentUrl, GetElementAttribute,
+    GetElementText, GetTimeouts, GetTitle, GetWindowHandle, GetWindowHandles, IsDisplayed,
+    NewWindow, PerformActions, ReleaseActions, SetTimeouts, Status, SwitchToWindow,
+    TakeElementScreenshot, TakeScreenshot,
 };
 use webdriver::response::{
-    CloseWindowResponse, CookieResponse, CookiesResponse, ElementRectResponse, NewSessionResponse,
-    NewWindowResponse, TimeoutsResponse, ValueResponse, WebDriverResponse, WindowRectResponse,
+    NewSessionResponse, NewWindowResponse, TimeoutsResponse, ValueResponse, WebDriverResponse,
 };
-use std::collections::HashMap;
-use std::sync::OnceLock;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 use std::str;
 use std::process::Child;
 use std::io::{BufReader, BufRead};
@@ -41,7 +42,22 @@ use uuid::Uuid;
 
  #[derive(Clone, PartialEq, Eq, Debug)]
  pub enum DuckDuckGoExtensionRoute {
-     GetContext
+     /// `GET /session/{sessionId}/ddg/context`
+     GetContext,
+     /// `POST /session/{sessionId}/ddg/context`
+     SetContext,
+     /// `POST /session/{sessionId}/ddg/privacy-config`
+     SetPrivacyConfig,
+     /// `GET /session/{sessionId}/ddg/privacy-config`
+     GetPrivacyConfig,
+     /// `POST /session/{sessionId}/ddg/protection`
+     SetProtection,
+     /// `GET /session/{sessionId}/ddg/trackers`
+     GetTrackerReport,
+     /// `POST /session/{sessionId}/ddg/clear-data`
+     ClearData,
+     /// `POST /session/{sessionId}/ddg/feature-flags`
+     SetFeatureFlags,
  }
 
  impl WebDriverExtensionRoute for DuckDuckGoExtensionRoute {
@@ -50,102 +66,557 @@ use uuid::Uuid;
     fn command(
         &self,
         _params: &Parameters,
-        _body_data: &Value,
+        body_data: &Value,
     ) -> WebDriverResult<WebDriverCommand<DuckDuckGoExtensionCommand>> {
         use self::DuckDuckGoExtensionRoute::*;
 
         let command = match *self {
-            GetContext => DuckDuckGoExtensionCommand::GetContext
+            GetContext => DuckDuckGoExtensionCommand::GetContext,
+            SetContext => {
+                let parameters: DuckDuckGoContextParameters = serde_json::from_value(body_data.clone()).map_err(|e| {
+                    WebDriverError::new(ErrorStatus::InvalidArgument, format!("Invalid context: {}", e))
+                })?;
+                DuckDuckGoExtensionCommand::SetContext(parameters)
+            }
+            SetPrivacyConfig => {
+                let body = body_data.as_object().ok_or_else(|| {
+                    WebDriverError::new(ErrorStatus::InvalidArgument, "Expected a JSON object of ddg:privacyConfig* entries")
+                })?;
+                DuckDuckGoExtensionCommand::SetPrivacyConfig(Box::new(DdgCapabilities::privacy_config_change(body)?))
+            }
+            GetPrivacyConfig => DuckDuckGoExtensionCommand::GetPrivacyConfig,
+            SetProtection => {
+                let parameters: DuckDuckGoProtectionParameters = serde_json::from_value(body_data.clone()).map_err(|e| {
+                    WebDriverError::new(ErrorStatus::InvalidArgument, format!("Invalid protection toggle: {}", e))
+                })?;
+                if parameters.domain.as_deref() == Some("") {
+                    return Err(WebDriverError::new(ErrorStatus::InvalidArgument, "domain must not be empty"));
+                }
+                DuckDuckGoExtensionCommand::SetProtection(parameters)
+            }
+            GetTrackerReport => DuckDuckGoExtensionCommand::GetTrackerReport,
+            ClearData => {
+                let parameters: DuckDuckGoClearDataParameters = serde_json::from_value(body_data.clone()).map_err(|e| {
+                    WebDriverError::new(ErrorStatus::InvalidArgument, format!("Invalid clear data options: {}", e))
+                })?;
+                DuckDuckGoExtensionCommand::ClearData(parameters)
+            }
+            SetFeatureFlags => {
+                let body = body_data.as_object().ok_or_else(|| {
+                    WebDriverError::new(ErrorStatus::InvalidArgument, "Expected a JSON object with ddg:featureFlags")
+                })?;
+                DuckDuckGoExtensionCommand::SetFeatureFlags(DdgCapabilities::feature_flags_change(body)?)
+            }
         };
 
         Ok(WebDriverCommand::Extension(command))
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// What element lookups of a session target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DuckDuckGoContext {
+    /// The web page in the current tab
+    #[default]
     Content,
+    /// The browser's native UI, through the automation server
     Chrome,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DuckDuckGoContextParameters {
-   //pub context: DuckDuckGoContext,
-}
-
-#[derive(Clone, Debug)]
-pub struct VoidWebDriverExtensionCommand;
-
-impl WebDriverExtensionCommand for VoidWebDriverExtensionCommand {
-    fn parameters_json(&self) -> Option<Value> {
-        panic!("No extensions implemented");
-    }
+    pub context: DuckDuckGoContext,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct AddonPath {
-    pub path: String,
-    pub temporary: Option<bool>,
-    #[serde(rename = "allowPrivateBrowsing")]
-    pub allow_private_browsing: Option<bool>,
+pub struct DuckDuckGoProtectionParameters {
+    /// Domain to toggle; the current tab's when not given
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// `false` adds the domain to the allowlist, `true` removes it
+    pub protected: bool,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub struct AddonUninstallParameters {
-    pub id: String,
+/// Options for the app's Fire / clear browsing data flow
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct DuckDuckGoClearDataParameters {
+    pub scope: ClearDataScope,
+    /// Spare the sites the user fireproofed
+    pub keep_fireproofed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ClearDataScope {
+    /// Cookies, storage, history and tabs of every site
+    #[default]
+    All,
+    /// Only the data of the current tab's site
+    CurrentSite,
 }
 
 #[derive(Clone, Debug)]
 pub enum DuckDuckGoExtensionCommand {
-    GetContext
+    GetContext,
+    SetContext(DuckDuckGoContextParameters),
+    /// Install a new privacy config into the session's running app
+    SetPrivacyConfig(Box<DdgCapabilities>),
+    /// Report the privacy config the session's app was given
+    GetPrivacyConfig,
+    /// Turn protections for a site on or off
+    SetProtection(DuckDuckGoProtectionParameters),
+    /// Report the current tab's third-party requests
+    GetTrackerReport,
+    /// Clear browsing data and wait for it to finish
+    ClearData(DuckDuckGoClearDataParameters),
+    /// Override feature flags in the running app
+    SetFeatureFlags(FeatureFlags),
 }
 
 impl WebDriverExtensionCommand for DuckDuckGoExtensionCommand {
     fn parameters_json(&self) -> Option<Value> {
         use self::DuckDuckGoExtensionCommand::*;
         match self {
-            GetContext => None
+            SetContext(parameters) => Some(serde_json::to_value(parameters).unwrap()),
+            SetProtection(parameters) => Some(serde_json::to_value(parameters).unwrap()),
+            ClearData(parameters) => Some(serde_json::to_value(parameters).unwrap()),
+            SetFeatureFlags(flags) => Some(serde_json::json!({ "ddg:featureFlags": flags })),
+            GetContext | SetPrivacyConfig(_) | GetPrivacyConfig | GetTrackerReport => None
         }
     }
 }
 
 
-#[derive(Default)]
 pub(crate) struct Handler {
+    /// Live sessions, keyed by session id
+    sessions: Mutex<HashMap<String, Arc<DriverSession>>>,
+    /// What sessions that are starting or live hold
+    reservations: Mutex<Reservations>,
+    /// Signalled whenever a session gives its reservations back
+    released: Condvar,
+    /// Serializes simulator selection so two sessions never pick the same device
+    simulator_selection: Mutex<()>,
+    /// Most sessions that may be starting or live at once
+    max_sessions: usize,
+    /// Pre-booted simulators iOS sessions lease before falling back to their own
+    pool: Option<Arc<SimulatorPool>>,
+    /// Apps `ddg:reuseApp` sessions left running
+    kept_apps: Mutex<Vec<KeptApp>>,
+    /// Content hash of the app a `ddg:reuseApp` session installed on each simulator
+    installed_apps: Mutex<HashMap<String, String>>,
+    /// Sessions without a command for this long are ended
+    idle_timeout: Option<Duration>,
+    /// Remote configs fetched for earlier sessions
+    config_cache: ConfigCache,
+}
+
+#[derive(Default)]
+struct Reservations {
+    sessions: usize,
+    ports: HashSet<u16>,
+    simulators: HashSet<String>,
+    /// Only one instance of the macOS app can run, so only one macOS session
+    macos_app: bool,
+    /// The driver is exiting; no new sessions may start
+    shutting_down: bool,
 }
 
 impl Handler {
-    pub fn new() -> Self {
-        Handler {}
+    pub fn new(
+        max_sessions: usize,
+        pool: Option<Arc<SimulatorPool>>,
+        idle_timeout: Option<Duration>,
+        config_cache: ConfigCache,
+    ) -> Self {
+        Handler {
+            sessions: Mutex::new(HashMap::new()),
+            reservations: Mutex::new(Reservations::default()),
+            released: Condvar::new(),
+            simulator_selection: Mutex::new(()),
+            max_sessions,
+            pool,
+            kept_apps: Mutex::new(Vec::new()),
+            installed_apps: Mutex::new(HashMap::new()),
+            idle_timeout,
+            config_cache,
+        }
     }
-}
 
-struct PortManager {
-    ports: Mutex<HashMap<&'static str, u16>>,
-}
+    /// Wait until another session may start on `platform`, queueing behind
+    /// the sessions that are starting or live
+    fn reserve_session(&self, platform: Platform) -> WebDriverResult<SessionSlot<'_>> {
+        let mut reservations = self.reservations.lock().unwrap();
+        loop {
+            if reservations.shutting_down {
+                return Err(WebDriverError::new(ErrorStatus::SessionNotCreated, "The driver is shutting down"));
+            }
+            if reservations.sessions < self.max_sessions && !(platform == Platform::MacOS && reservations.macos_app) {
+                break;
+            }
+            info!("Waiting for a free session slot ({} of {} in use)", reservations.sessions, self.max_sessions);
+            reservations = self.released.wait(reservations).unwrap();
+        }
+        reservations.sessions += 1;
+        if platform == Platform::MacOS {
+            reservations.macos_app = true;
+        }
+        Ok(SessionSlot {
+            handler: self,
+            platform,
+            port: None,
+            simulator: None,
+            leased: false,
+            started: false,
+        })
+    }
+
+    /// Give back what a session held and wake up queued NewSession requests
+    fn release(&self, platform: Platform, port: Option<u16>, simulator: Option<&str>) {
+        {
+            let mut reservations = self.reservations.lock().unwrap();
+            reservations.sessions -= 1;
+            if platform == Platform::MacOS {
+                reservations.macos_app = false;
+            }
+        }
+        self.release_resources(port, simulator);
+    }
+
+    /// Give back a port and simulator, e.g. those of a kept app nobody attached to
+    fn release_resources(&self, port: Option<u16>, simulator: Option<&str>) {
+        let mut reservations = self.reservations.lock().unwrap();
+        if let Some(port) = port {
+            reservations.ports.remove(&port);
+            forget_request_format(port);
+        }
+        if let Some(udid) = simulator {
+            reservations.simulators.remove(udid);
+        }
+        self.released.notify_all();
+    }
+
+    fn session(&self, session_id: &Option<String>) -> WebDriverResult<Arc<DriverSession>> {
+        let session_id = session_id.as_ref().expect("Expected a session id");
+        self.sessions.lock().unwrap().get(session_id).cloned().ok_or_else(|| {
+            WebDriverError::new(ErrorStatus::InvalidSessionId, format!("Unknown session {}", session_id))
+        })
+    }
+
+    /// Tear down a session: stop the app or simulator it drove and its log stream
+    fn end_session(&self, session_id: &str) {
+        let Some(session) = self.sessions.lock().unwrap().remove(session_id) else {
+            return;
+        };
+        info!("Ending session {} ({:?}, {})", session_id, session.platform, session.app_path);
+        self.close_session(&session);
+    }
+
+    fn close_session(&self, session: &DriverSession) {
+        if let Some(ref app_hash) = session.app_hash {
+            self.keep_app(session, app_hash);
+            return;
+        }
+        self.stop_app(session.platform, session.port, &session.bundle_id, session.simulator_udid.as_deref());
+        if let Some(child) = session.log_stream.lock().unwrap().take() {
+            stop_logs(child);
+        }
+        if let Some(ref dir) = session.extracted_app {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        self.release(session.platform, Some(session.port), session.simulator_udid.as_deref());
+    }
 
-impl PortManager {
-    fn new() -> Self {
-        PortManager {
-            ports: Mutex::new(HashMap::new()),
+    /// End sessions that have gone without a command for longer than the idle timeout
+    fn reap_idle_sessions(&self, idle_timeout: Duration) {
+        let idle: Vec<(String, Arc<DriverSession>)> = {
+            let mut sessions = self.sessions.lock().unwrap();
+            let ids: Vec<String> = sessions
+                .iter()
+                .filter(|(_, session)| session.idle_for().is_some_and(|idle| idle > idle_timeout))
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.into_iter().filter_map(|id| sessions.remove(&id).map(|session| (id, session))).collect()
+        };
+        for (session_id, session) in idle {
+            info!("Ending session {} after {:?} without commands", session_id, idle_timeout);
+            self.close_session(&session);
         }
     }
 
-    fn get_port(&self, udid: &str) -> u16 {
-        let mut ports = self.ports.lock().unwrap();
-        if let Some(&port) = ports.get(udid) {
-            return port;
+    /// Check for idle sessions in the background, if there is an idle timeout
+    pub fn spawn_idle_reaper(self: &Arc<Self>) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        let handler = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            thread::sleep(IDLE_CHECK_INTERVAL.min(idle_timeout));
+            match handler.upgrade() {
+                Some(handler) => handler.reap_idle_sessions(idle_timeout),
+                None => return,
+            }
+        });
+    }
+
+    /// End every session and stop kept apps and pool simulators before the
+    /// driver exits. Sessions that are still starting are waited for, for up
+    /// to `SHUTDOWN_TIMEOUT`, so their apps get stopped too.
+    pub fn shutdown(&self) {
+        self.reservations.lock().unwrap().shutting_down = true;
+        self.released.notify_all();
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        loop {
+            let session_ids: Vec<String> = self.sessions.lock().unwrap().keys().cloned().collect();
+            for session_id in session_ids {
+                self.end_session(&session_id);
+            }
+            let reservations = self.reservations.lock().unwrap();
+            if reservations.sessions == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("{} session(s) still starting at shutdown", reservations.sessions);
+                break;
+            }
+            info!("Waiting for {} starting session(s) before shutting down", reservations.sessions);
+            let _ = self.released.wait_timeout(reservations, Duration::from_secs(1)).unwrap();
+        }
+        let kept_apps: Vec<KeptApp> = self.kept_apps.lock().unwrap().drain(..).collect();
+        for kept in kept_apps {
+            self.discard_kept_app(kept);
         }
+        if let Some(ref pool) = self.pool {
+            pool.shutdown();
+        }
+    }
 
+    /// Quit the macOS app, or shut down the simulator (or hand it back to the pool)
+    fn stop_app(&self, platform: Platform, port: u16, bundle_id: &str, simulator_udid: Option<&str>) {
+        match platform {
+            Platform::MacOS => quit_macos_app(port, bundle_id),
+            Platform::IOS => {
+                if let Some(udid) = simulator_udid {
+                    match self.pool {
+                        Some(ref pool) if pool.contains(udid) => pool.give_back(udid),
+                        _ => {
+                            xcrun_command(&["simctl", "shutdown", udid]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Leave a `ddg:reuseApp` session's app running for the next session.
+    /// Only the session slot is given back; the port and simulator stay reserved.
+    fn keep_app(&self, session: &DriverSession, app_hash: &str) {
+        info!("Keeping the app on port {} running for the next session", session.port);
+        self.kept_apps.lock().unwrap().push(KeptApp {
+            platform: session.platform,
+            port: session.port,
+            simulator_udid: session.simulator_udid.clone(),
+            device: session.device.clone(),
+            bundle_id: session.bundle_id.clone(),
+            app_path: session.app_path.clone(),
+            app_hash: app_hash.to_string(),
+            ddg_caps: session.ddg_caps.lock().unwrap().clone(),
+            privacy_config: session.privacy_config.lock().unwrap().clone(),
+            extracted_app: session.extracted_app.clone(),
+            log_stream: session.log_stream.lock().unwrap().take(),
+        });
+        self.release(session.platform, None, None);
+    }
+
+    /// Take the kept app for `platform` and, on iOS, the simulator's device type and runtime
+    fn take_kept_app(&self, platform: Platform, device: Option<(&str, &str)>) -> Option<KeptApp> {
+        let mut kept_apps = self.kept_apps.lock().unwrap();
+        let index = kept_apps.iter().position(|kept| {
+            kept.platform == platform && kept.device.as_ref().map(|(device, os)| (device.as_str(), os.as_str())) == device
+        })?;
+        Some(kept_apps.remove(index))
+    }
+
+    /// Stop a kept app no session is going to attach to
+    fn discard_kept_app(&self, mut kept: KeptApp) {
+        info!("Stopping the kept app on port {}", kept.port);
+        self.stop_app(kept.platform, kept.port, &kept.bundle_id, kept.simulator_udid.as_deref());
+        self.forget_kept_app(&mut kept);
+        self.release_resources(Some(kept.port), kept.simulator_udid.as_deref());
+    }
+
+    /// Take over a kept iOS app's simulator to reinstall or relaunch the app on it
+    fn adopt_simulator(&self, slot: &mut SessionSlot<'_>, mut kept: KeptApp) -> String {
+        let udid = kept.simulator_udid.clone().expect("Kept iOS apps run on a simulator");
+        info!("Reusing simulator {} of the kept app", udid);
+        self.forget_kept_app(&mut kept);
+        self.release_resources(Some(kept.port), None);
+        slot.simulator = Some(udid.clone());
+        udid
+    }
+
+    /// Stop what a kept app holds besides its port and simulator
+    fn forget_kept_app(&self, kept: &mut KeptApp) {
+        if let Some(child) = kept.log_stream.take() {
+            stop_logs(child);
+        }
+        if let Some(ref dir) = kept.extracted_app {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+
+    /// Start a session on a kept app that was just reset for it
+    fn attach_kept_app(
+        &self,
+        mut slot: SessionSlot<'_>,
+        kept: KeptApp,
+        app: AppBundle,
+        ddg_caps: DdgCapabilities,
+        timeouts: Timeouts,
+    ) -> WebDriverResult<WebDriverResponse> {
+        info!("Attaching to the running app on port {}", kept.port);
+        slot.port = Some(kept.port);
+        slot.simulator = kept.simulator_udid.clone();
+        // The running app was started from the kept bundle
+        app.remove_extracted();
+        negotiate_request_format(kept.port);
+        let content_blocker_ready = wait_for_content_blocker(kept.port);
+        let os_runtime = match kept.platform {
+            Platform::MacOS => get_macos_version(),
+            Platform::IOS => kept.device.as_ref().map(|(_, os)| os.clone()),
+        };
+        let session = DriverSession {
+            platform: kept.platform,
+            port: kept.port,
+            simulator_udid: kept.simulator_udid,
+            device: kept.device,
+            bundle_id: kept.bundle_id,
+            app_path: kept.app_path,
+            extracted_app: kept.extracted_app,
+            ddg_caps: Mutex::new(ddg_caps),
+            privacy_config: Mutex::new(kept.privacy_config),
+            context: Mutex::new(DuckDuckGoContext::default()),
+            app_hash: Some(kept.app_hash),
+            log_stream: Mutex::new(kept.log_stream),
+            capabilities: Map::new(),
+            timeouts: Mutex::new(timeouts),
+            last_activity: Mutex::new(Instant::now()),
+            active_commands: AtomicUsize::new(0),
+        };
+        Ok(self.register_session(slot, session, os_runtime, content_blocker_ready))
+    }
+
+    /// Make a started session live and build its NewSession response
+    fn register_session(
+        &self,
+        slot: SessionSlot<'_>,
+        mut session: DriverSession,
+        os_runtime: Option<String>,
+        content_blocker_ready: Option<std::time::Duration>,
+    ) -> WebDriverResponse {
+        let session_id = Uuid::new_v4().to_string();
+        session.capabilities = session_capabilities(&session, os_runtime, content_blocker_ready);
+        let capabilities = Value::Object(session.capabilities.clone());
+        self.sessions.lock().unwrap().insert(session_id.clone(), Arc::new(session));
+        slot.started();
+        WebDriverResponse::NewSession(NewSessionResponse {
+            session_id,
+            capabilities,
+        })
+    }
+}
+
+/// Reset a kept app for a new session, reporting whether it is usable
+fn reset_kept_app(kept: &KeptApp) -> bool {
+    match reset_app_state(kept.port) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Kept app on port {} could not be reset: {}", kept.port, e);
+            false
+        }
+    }
+}
+
+/// How often sessions are checked against the idle timeout
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest the driver waits for sessions that are still starting when it exits
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A place among the concurrent sessions, and the port and simulator picked
+/// for it. Dropping it gives them back unless the session started.
+struct SessionSlot<'a> {
+    handler: &'a Handler,
+    platform: Platform,
+    port: Option<u16>,
+    simulator: Option<String>,
+    /// The simulator came from the pool and goes back to it
+    leased: bool,
+    started: bool,
+}
+
+impl SessionSlot<'_> {
+    /// First free automation port that no other session is using. Each
+    /// candidate is reserved before it is probed, so the reservations lock
+    /// isn't held while binding.
+    fn allocate_port(&mut self) -> WebDriverResult<u16> {
         for port in 8557..=65535 {
+            if !self.handler.reservations.lock().unwrap().ports.insert(port) {
+                continue;
+            }
             if port_is_available(port) {
-                ports.insert(Box::leak(udid.to_string().into_boxed_str()), port);
-                return port;
+                self.port = Some(port);
+                return Ok(port);
+            }
+            self.handler.reservations.lock().unwrap().ports.remove(&port);
+        }
+        Err(WebDriverError::new(ErrorStatus::SessionNotCreated, "No available automation ports"))
+    }
+
+    /// A simulator no other session is using
+    fn select_simulator(&mut self, target_device: &str, target_os: &str) -> Result<String, String> {
+        let _selection = self.handler.simulator_selection.lock().unwrap();
+        let in_use = self.handler.reservations.lock().unwrap().simulators.clone();
+        let udid = find_or_create_simulator(target_device, target_os, &in_use)?;
+        self.handler.reservations.lock().unwrap().simulators.insert(udid.clone());
+        self.simulator = Some(udid.clone());
+        Ok(udid)
+    }
+
+    /// A ready pool simulator, if the pool was built for this device, runtime and app
+    fn lease_simulator(&mut self, target_device: &str, target_os: &str, app_path: &str) -> Option<String> {
+        let udid = self.handler.pool.as_ref()?.lease(target_device, target_os, app_path)?;
+        self.handler.reservations.lock().unwrap().simulators.insert(udid.clone());
+        self.simulator = Some(udid.clone());
+        self.leased = true;
+        Some(udid)
+    }
+
+    /// The session is live; its reservations are given back by `end_session`
+    fn started(mut self) {
+        self.started = true;
+    }
+}
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        if !self.started {
+            if let (true, Some(pool), Some(udid)) = (self.leased, &self.handler.pool, &self.simulator) {
+                pool.give_back(udid);
             }
+            self.handler.release(self.platform, self.port, self.simulator.as_deref());
         }
-        panic!("No available ports found");
+    }
+}
+
+/// Report a transport timeout during script execution as a script timeout
+fn script_timeout(err: WebDriverError) -> WebDriverError {
+    if err.error == ErrorStatus::Timeout {
+        WebDriverError::new(ErrorStatus::ScriptTimeout, err.message)
+    } else {
+        err
     }
 }
 
@@ -153,87 +624,62 @@ fn port_is_available(port: u16) -> bool {
     std::net::TcpListener::bind(("0.0.0.0", port)).is_ok()
 }
 
-static PORT_MANAGER: OnceLock<PortManager> = OnceLock::new();
-
-fn get_port(udid: &str) -> u16 {
-    let port_manager = PORT_MANAGER.get_or_init(|| PortManager::new());
-    port_manager.get_port(udid)
+/// Name of the `index`th simulator the driver keeps for a device type and runtime
+fn simulator_name(target_device: &str, target_os: &str, index: usize) -> String {
+    if index == 1 {
+        format!("{target_device} {target_os} (webdriver)")
+    } else {
+        format!("{target_device} {target_os} (webdriver {index})")
+    }
 }
 
-fn server_request(udid: &str, method: &str, params: &std::collections::HashMap<&str, &str>) -> String {
-    let mut child = monitor_simulator_logs(&udid);
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    thread::spawn(move || {
-        let reader = BufReader::new(stdout);
-        info!("Simulator logs:");
-        for line in reader.lines() {
-            if let Ok(log_line) = line {
-                info!("{}", log_line);
-            }
-        }
-        info!("Simulator logs end");
-    });
-    let port = get_port(udid);
-    let query_string: String = params.iter()
-        .map(|(key, value)| format!("{}={}", key, value))
-        .collect::<Vec<String>>()
-        .join("&");
-    let url = format!("http://localhost:{}/{method}?{}", port, query_string);
-    info!("URL to send: {:?}", url);
-    let client = reqwest::blocking::Client::new();
-    let resp = client.get(url)
-        .timeout(std::time::Duration::from_secs(30)) // TODO Handle variadic timeout set by command
-        .send()
-        .map_err(|e| {
-            if e.is_timeout() {
-            info!("Request timed out");
-            // TODO construct serialised error like: Error::new(ErrorKind::TimedOut, "Request timed out")
-            "Request timed out".to_string()
-            } else {
-            "Other error".to_string()
-            }
-        })
-        .expect("Failed to send request")
-        .text()
-        .expect("Failed to read response text");
-    info!("Response: {:#?}", resp);
-    #[derive(Deserialize)]
-    struct Response {
-        message: String,
-    }
-    let json: Response = serde_json::from_str(&resp).expect("Failed to parse response");
-    let _ = child.kill();
-    return json.message;
+/// Inverse of `simulator_name`: which of our simulators `name` is, if any
+fn simulator_index(name: &str, target_device: &str, target_os: &str) -> Option<usize> {
+    if name == simulator_name(target_device, target_os, 1) {
+        return Some(1);
+    }
+    name.strip_prefix(&format!("{target_device} {target_os} (webdriver "))?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
+        .filter(|index| *index > 1)
 }
 
-fn find_or_create_simulator(target_device: &str, target_os: &str) -> Result<String, String> {
+/// Pick a simulator for a new session that no other session is using,
+/// creating another one if all of ours are taken
+fn find_or_create_simulator(target_device: &str, target_os: &str, in_use: &HashSet<String>) -> Result<String, String> {
     // Step 1: List existing simulators
     let list_output = xcrun_command(&["simctl", "list", "devices", "-j"]);
-    let device_name = format!("{target_device} {target_os} (webdriver)");
 
     let list_stdout = str::from_utf8(&list_output.stdout).expect("Invalid UTF-8 in simulator list");
     let simulators: serde_json::Value = serde_json::from_str(list_stdout).expect("Failed to parse simulator list");
 
-    // Step 2: Search for a matching simulator (prefer Shutdown, but accept Booted)
+    // Step 2: Search for a free matching simulator (prefer Shutdown, but accept Booted)
     let mut booted_candidate: Option<String> = None;
+    let mut taken_indexes = HashSet::new();
     if let Some(devices) = simulators.get("devices") {
         for (runtime, device_list) in devices.as_object().unwrap() {
             info!("Runtime: {:?}", runtime);
             if runtime.contains(target_os) {
                 for device in device_list.as_array().unwrap() {
-                    if device["name"] == device_name && device["isAvailable"] == true {
-                        let state = device["state"].as_str().unwrap_or("");
-                        let udid = device["udid"].as_str().unwrap().to_string();
-                        
-                        if state == "Shutdown" {
-                            // Prefer shutdown simulators - return immediately
-                            info!("Found matching shutdown simulator {:?}", device);
-                            return Ok(udid);
-                        } else if state == "Booted" && booted_candidate.is_none() {
-                            // Remember first booted simulator as fallback
-                            info!("Found matching booted simulator {:?}", device);
-                            booted_candidate = Some(udid);
-                        }
+                    let name = device["name"].as_str().unwrap_or("");
+                    let Some(index) = simulator_index(name, target_device, target_os) else {
+                        continue;
+                    };
+                    taken_indexes.insert(index);
+                    let udid = device["udid"].as_str().unwrap().to_string();
+                    if device["isAvailable"] != true || in_use.contains(&udid) {
+                        continue;
+                    }
+                    let state = device["state"].as_str().unwrap_or("");
+                    if state == "Shutdown" {
+                        // Prefer shutdown simulators - return immediately
+                        info!("Found matching shutdown simulator {:?}", device);
+                        return Ok(udid);
+                    } else if state == "Booted" && booted_candidate.is_none() {
+                        // Remember first booted simulator as fallback
+                        info!("Found matching booted simulator {:?}", device);
+                        booted_candidate = Some(udid);
                     }
                 }
             }
@@ -246,9 +692,11 @@ fn find_or_create_simulator(target_device: &str, target_os: &str) -> Result<Stri
         return Ok(udid);
     }
     
-    info!("No matching simulator found, creating a new one...");
+    info!("No free matching simulator found, creating a new one...");
 
-    // Step 3: Create a new simulator if no match is found
+    // Step 3: Create a new simulator under the first unused name
+    let index = (1..).find(|index| !taken_indexes.contains(index)).unwrap();
+    let device_name = simulator_name(target_device, target_os, index);
     let create_output = xcrun_command(&[
         "simctl",
         "create",
@@ -257,6 +705,21 @@ fn find_or_create_simulator(target_device: &str, target_os: &str) -> Result<Stri
         &("com.apple.CoreSimulator.SimRuntime.".to_owned() + target_os),
     ]);
 
+    if !create_output.status.success() {
+        return Err("Failed to create a new simulator".to_string());
+    }
+
+    let new_udid = str::from_utf8(&create_output.stdout)
+        .expect("Invalid UTF-8 in create simulator output")
+        .trim();
+
+    install_ca_cert(new_udid);
+
+    Ok(new_udid.to_string())
+}
+
+/// Trust the test server's CA on a simulator
+pub(crate) fn install_ca_cert(udid: &str) {
     let cargo_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let path_to_ca_cert = cargo_path.join("cacert.pem");
 
@@ -264,30 +727,32 @@ fn find_or_create_simulator(target_device: &str, target_os: &str) -> Result<Stri
     let _install_ca_key = xcrun_command(&[
         "simctl",
         "keychain",
-        &device_name,
+        udid,
         "add-root-cert",
         path_to_ca_cert.to_str().expect("Failed to convert path to string"),
     ]);
-
-    if !create_output.status.success() {
-        return Err("Failed to create a new simulator".to_string());
-    }
-
-    let new_udid = str::from_utf8(&create_output.stdout)
-        .expect("Invalid UTF-8 in create simulator output")
-        .trim();
-    Ok(new_udid.to_string())
 }
 
 // Platform configuration
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Platform {
     IOS,
     MacOS,
 }
 
 impl Platform {
-    fn from_env() -> Self {
+    /// Parse a `platformName` capability value
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "ios" => Some(Platform::IOS),
+            "mac" | "macos" => Some(Platform::MacOS),
+            _ => None,
+        }
+    }
+
+    /// Default platform for sessions that do not request one
+    pub fn from_env() -> Self {
         let env_value = std::env::var("TARGET_PLATFORM");
         info!("TARGET_PLATFORM env var: {:?}", env_value);
         match env_value.as_deref() {
@@ -306,10 +771,18 @@ impl Platform {
         }
     }
 
+    /// `platformName` as reported in capabilities
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::IOS => "ios",
+            Platform::MacOS => "mac",
+        }
+    }
+
     fn bundle_id(&self) -> &'static str {
         match self {
-            Platform::IOS => "com.duckduckgo.mobile.ios",
-            Platform::MacOS => "com.duckduckgo.macos.browser",
+            Platform::IOS => APP_BUNDLE_ID_IOS,
+            Platform::MacOS => APP_BUNDLE_ID_MACOS,
         }
     }
 }
@@ -321,83 +794,11 @@ const APP_BUNDLE_ID_MACOS_DEBUG: &str = "com.duckduckgo.macos.browser.debug";
 // Development team ID for DuckDuckGo macOS apps
 const MACOS_DEVELOPMENT_TEAM: &str = "HKE973VLUW";
 
-/// DuckDuckGo-specific session capabilities
-#[derive(Clone, Debug, Default)]
-pub struct DdgCapabilities {
-    /// Custom URL for privacy configuration (overrides bundled config via cache write)
-    pub privacy_config_url: Option<String>,
-    /// Local file path for privacy configuration (uses TEST_PRIVACY_CONFIG_PATH env var)
-    pub privacy_config_path: Option<String>,
-}
-
-impl DdgCapabilities {
-    /// Parse DuckDuckGo capabilities from WebDriver NewSession parameters
-    pub fn from_new_session_params(params: &webdriver::command::NewSessionParameters) -> Self {
-        let mut caps = DdgCapabilities::default();
-        
-        // NewSessionParameters is an enum, try multiple serialization approaches
-        if let Ok(value) = serde_json::to_value(params) {
-            info!("Serialized NewSessionParameters: {}", value);
-            
-            // Approach 1: Direct capabilities.alwaysMatch (Spec variant)
-            if let Some(capabilities) = value.get("capabilities") {
-                if let Some(always_match) = capabilities.get("alwaysMatch") {
-                    caps.extract_from_value(always_match);
-                }
-                if let Some(first_match) = capabilities.get("firstMatch").and_then(|v| v.as_array()) {
-                    for match_caps in first_match {
-                        caps.extract_from_value(match_caps);
-                    }
-                }
-            }
-            
-            // Approach 2: Spec enum variant wrapper { "Spec": { "alwaysMatch": ... } }
-            if caps.privacy_config_url.is_none() {
-                if let Some(spec) = value.get("Spec") {
-                    if let Some(always_match) = spec.get("alwaysMatch") {
-                        caps.extract_from_value(always_match);
-                    }
-                    if let Some(first_match) = spec.get("firstMatch").and_then(|v| v.as_array()) {
-                        for match_caps in first_match {
-                            caps.extract_from_value(match_caps);
-                        }
-                    }
-                }
-            }
-            
-            // Approach 3: Direct alwaysMatch at root level
-            if caps.privacy_config_url.is_none() {
-                if let Some(always_match) = value.get("alwaysMatch") {
-                    caps.extract_from_value(always_match);
-                }
-            }
-        } else {
-            info!("Failed to serialize NewSessionParameters to JSON");
-        }
-        
-        info!("Parsed DuckDuckGo capabilities: {:?}", caps);
-        caps
-    }
-    
-    fn extract_from_value(&mut self, caps: &Value) {
-        // Look for ddg:privacyConfigURL (writes to cache)
-        if let Some(url) = caps.get("ddg:privacyConfigURL").and_then(|v| v.as_str()) {
-            info!("Found ddg:privacyConfigURL: {}", url);
-            self.privacy_config_url = Some(url.to_string());
-        }
-        // Look for ddg:privacyConfigPath (uses TEST_PRIVACY_CONFIG_PATH env var)
-        if let Some(path) = caps.get("ddg:privacyConfigPath").and_then(|v| v.as_str()) {
-            info!("Found ddg:privacyConfigPath: {}", path);
-            self.privacy_config_path = Some(path.to_string());
-        }
-    }
-}
-
 // Get bundle ID from app's Info.plist
 fn get_macos_bundle_id(app_path: &str) -> String {
     let info_plist = format!("{}/Contents/Info.plist", app_path);
     let output = Command::new("defaults")
-        .args(&["read", &info_plist, "CFBundleIdentifier"])
+        .args(["read", &info_plist, "CFBundleIdentifier"])
         .output();
     
     match output {
@@ -415,10 +816,79 @@ fn get_macos_bundle_id(app_path: &str) -> String {
     }
 }
 
+// Get the marketing version (CFBundleShortVersionString) from an app's Info.plist
+fn get_app_version(info_plist: &str) -> Option<String> {
+    let output = Command::new("defaults")
+        .args(["read", info_plist, "CFBundleShortVersionString"])
+        .output();
+
+    match output {
+        Ok(out) if out.status.success() => {
+            Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+        },
+        _ => {
+            info!("Could not read app version from {}", info_plist);
+            None
+        }
+    }
+}
+
+// Get the version of macOS the driver is running on
+fn get_macos_version() -> Option<String> {
+    let output = Command::new("sw_vers").arg("-productVersion").output();
+    match output {
+        Ok(out) if out.status.success() => {
+            Some(String::from_utf8_lossy(&out.stdout).trim().to_string())
+        },
+        _ => None,
+    }
+}
+
+/// Capabilities reported back to the client for a freshly started session
+fn session_capabilities(
+    session: &DriverSession,
+    os_runtime: Option<String>,
+    content_blocker_ready: Option<std::time::Duration>,
+) -> Map<String, Value> {
+    let ddg_caps = session.ddg_caps.lock().unwrap();
+    let device = session.device.as_ref().map(|(device, _)| device);
+    let info_plist = info_plist(&session.app_path, session.platform);
+    let capabilities = serde_json::json!({
+        "browserName": "duckduckgo",
+        "browserVersion": get_app_version(&info_plist),
+        "platformName": session.platform.name(),
+        "acceptInsecureCerts": true,
+        "pageLoadStrategy": "normal",
+        "setWindowRect": false,
+        "strictFileInteractability": false,
+        "proxy": {},
+        "timeouts": session.timeouts().to_json(),
+        "ddg:deviceType": device,
+        "ddg:osRuntime": os_runtime,
+        "ddg:privacyConfigURL": ddg_caps.privacy_config_url,
+        "ddg:privacyConfigPath": ddg_caps.privacy_config_path,
+        "ddg:trackerDataURL": ddg_caps.tracker_data_url,
+        "ddg:surrogatesURL": ddg_caps.surrogates_url,
+        "ddg:featureFlags": ddg_caps.feature_flags.as_ref().map(|flags| feature_flags::resolved(session, flags)),
+        "ddg:reuseApp": session.app_hash.is_some(),
+        "ddgdriver:appHash": session.app_hash,
+        "ddgdriver:bundleId": session.bundle_id,
+        "ddgdriver:appPath": session.app_path,
+        "ddgdriver:simulatorUdid": session.simulator_udid,
+        "ddgdriver:automationPort": session.port,
+        // null when the content blocker did not report ready in time
+        "ddgdriver:contentBlockerReadyMs": content_blocker_ready.map(|elapsed| elapsed.as_millis() as u64),
+    });
+    match capabilities {
+        Value::Object(map) => map,
+        _ => unreachable!(),
+    }
+}
+
 // macOS-specific functions
 fn write_macos_defaults(bundle_id: &str, key: &str, key_type: &str, value: &str) {
     let output = Command::new("defaults")
-        .args(&[
+        .args([
             "write",
             bundle_id,
             key,
@@ -456,68 +926,6 @@ fn derive_macos_app_config_group(bundle_id: &str) -> String {
     format!("{}.{}.app-configuration{}", MACOS_DEVELOPMENT_TEAM, base_id, suffix)
 }
 
-/// Write a string value to the app configuration group defaults on macOS
-fn write_macos_app_config_defaults(bundle_id: &str, key: &str, value: &str) {
-    let group_id = derive_macos_app_config_group(bundle_id);
-    info!("Writing to app config group {}: {} = {}", group_id, key, value);
-    
-    let output = Command::new("defaults")
-        .args(&[
-            "write",
-            &group_id,
-            key,
-            "-string",
-            value,
-        ])
-        .output()
-        .expect("Failed to write app config defaults");
-    
-    if !output.status.success() {
-        info!(
-            "Failed to write app config defaults to {}: {}",
-            group_id,
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
-}
-
-/// Fetch privacy configuration from URL (supports http://, https://, and file://)
-fn fetch_privacy_config(config_url: &str) -> Result<Vec<u8>, String> {
-    info!("Fetching privacy config from: {}", config_url);
-    
-    if config_url.starts_with("file://") {
-        // Handle local file paths
-        let file_path = config_url.strip_prefix("file://").unwrap();
-        match std::fs::read(file_path) {
-            Ok(data) => {
-                info!("Read {} bytes from local file", data.len());
-                Ok(data)
-            },
-            Err(e) => Err(format!("Failed to read file {}: {}", file_path, e))
-        }
-    } else {
-        // Handle HTTP/HTTPS URLs
-        let client = reqwest::blocking::Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
-            .build()
-            .map_err(|e| format!("Failed to create HTTP client: {}", e))?;
-        
-        let response = client.get(config_url)
-            .send()
-            .map_err(|e| format!("Failed to fetch config: {}", e))?;
-        
-        if !response.status().is_success() {
-            return Err(format!("HTTP error: {}", response.status()));
-        }
-        
-        let data = response.bytes()
-            .map_err(|e| format!("Failed to read response body: {}", e))?;
-        
-        info!("Fetched {} bytes from URL", data.len());
-        Ok(data.to_vec())
-    }
-}
-
 /// Get the macOS app group container path
 fn get_macos_group_container_path(group_id: &str) -> Option<PathBuf> {
     // On macOS, group containers are at ~/Library/Group Containers/<group-id>/
@@ -532,22 +940,22 @@ fn get_macos_group_container_path(group_id: &str) -> Option<PathBuf> {
     }
 }
 
-/// Set up custom privacy configuration for macOS
+/// Set up a custom remote configuration for macOS
 /// This pre-fetches the config and writes it directly to the app's cache
-fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
+fn setup_macos_remote_config(bundle_id: &str, config: &RemoteConfig) -> InstallMode {
+    let kind = config.kind();
     let group_id = derive_macos_app_config_group(bundle_id);
-    info!("Setting up custom privacy config for {}", bundle_id);
+    info!("Setting up custom {} for {}", kind.label(), bundle_id);
     info!("  Config group: {}", group_id);
-    info!("  Config URL: {}", config_url);
+    info!("  Config: {}", config.fallback_url().unwrap_or("inline"));
     
     // Fetch the config data
-    let config_data = match fetch_privacy_config(config_url) {
+    let config_data = match config.data() {
         Ok(data) => data,
         Err(e) => {
-            info!("Failed to fetch privacy config: {}", e);
+            info!("Failed to fetch {}: {}", kind.label(), e);
             // Fall back to just setting the URL
-            set_macos_config_url(&group_id, config_url);
-            return;
+            return set_macos_config_url(&group_id, config);
         }
     };
     
@@ -556,26 +964,23 @@ fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
         Some(path) => path,
         None => {
             info!("Failed to get group container path, falling back to URL mode");
-            set_macos_config_url(&group_id, config_url);
-            return;
+            return set_macos_config_url(&group_id, config);
         }
     };
     
     // Create the directory if it doesn't exist
     if let Err(e) = std::fs::create_dir_all(&container_path) {
         info!("Failed to create group container directory: {}", e);
-        set_macos_config_url(&group_id, config_url);
-        return;
+        return set_macos_config_url(&group_id, config);
     }
     
-    // Write the config file (macOS uses "macos-config.json")
-    let config_file = container_path.join("macos-config.json");
+    // Write the config file (e.g. "macos-config.json" for the privacy config)
+    let config_file = container_path.join(kind.macos_file_name());
     info!("Writing config to: {:?}", config_file);
     
     if let Err(e) = std::fs::write(&config_file, &config_data) {
         info!("Failed to write config file: {}", e);
-        set_macos_config_url(&group_id, config_url);
-        return;
+        return set_macos_config_url(&group_id, config);
     }
     
     // CRITICAL: Write to the GROUP CONTAINER plist, not ~/Library/Preferences
@@ -587,8 +992,7 @@ fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
     // Create the Preferences directory if needed
     if let Err(e) = std::fs::create_dir_all(&prefs_dir) {
         info!("Failed to create Preferences directory: {}", e);
-        set_macos_config_url(&group_id, config_url);
-        return;
+        return set_macos_config_url(&group_id, config);
     }
     
     info!("Writing UserDefaults to: {:?}", plist_path);
@@ -599,12 +1003,12 @@ fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
     // Use PlistBuddy to set values in the correct plist
     // First, create the plist if it doesn't exist
     let _ = Command::new("/usr/libexec/PlistBuddy")
-        .args(&["-c", "Save", plist_path.to_str().unwrap()])
+        .args(["-c", "Save", plist_path.to_str().unwrap()])
         .output();
     
     // Set isInternalUser = true (required for custom config URLs to work)
-    let output = Command::new("/usr/libexec/PlistBuddy")
-        .args(&[
+    let _ = Command::new("/usr/libexec/PlistBuddy")
+        .args([
             "-c", "Delete :isInternalUser",
             plist_path.to_str().unwrap()
         ])
@@ -612,7 +1016,7 @@ fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
     // Ignore delete errors (key might not exist)
     
     let output = Command::new("/usr/libexec/PlistBuddy")
-        .args(&[
+        .args([
             "-c", "Add :isInternalUser bool true",
             plist_path.to_str().unwrap()
         ])
@@ -628,17 +1032,18 @@ fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
         info!("Set isInternalUser = true");
     }
     
-    // Set CustomConfigurationURL.privacyConfiguration
+    // Set CustomConfigurationURL.<config name>
+    let url_key = format!("CustomConfigurationURL.{}", kind.name());
     let _ = Command::new("/usr/libexec/PlistBuddy")
-        .args(&[
-            "-c", "Delete :CustomConfigurationURL.privacyConfiguration",
+        .args([
+            "-c", &format!("Delete :{}", url_key),
             plist_path.to_str().unwrap()
         ])
         .output();
     
     let output = Command::new("/usr/libexec/PlistBuddy")
-        .args(&[
-            "-c", &format!("Add :CustomConfigurationURL.privacyConfiguration string {}", file_url),
+        .args([
+            "-c", &format!("Add :{} string {}", url_key, file_url),
             plist_path.to_str().unwrap()
         ])
         .output()
@@ -650,19 +1055,28 @@ fn setup_macos_privacy_config(bundle_id: &str, config_url: &str) {
             String::from_utf8_lossy(&output.stderr)
         );
     } else {
-        info!("Set CustomConfigurationURL.privacyConfiguration = {}", file_url);
+        info!("Set {} = {}", url_key, file_url);
     }
+
+    // Mark the cached copy as current so the app doesn't replace it on launch
+    write_macos_defaults(bundle_id, kind.macos_etag_key(), "string", &content_etag(&config_data));
     
-    info!("Successfully pre-cached privacy config ({} bytes) and set UserDefaults in group container", config_data.len());
+    info!("Successfully pre-cached {} ({} bytes) and set UserDefaults in group container", kind.label(), config_data.len());
+    InstallMode::CacheWrite
 }
 
 /// Fallback: Set the custom config URL (used if pre-fetching fails)
-fn set_macos_config_url(group_id: &str, config_url: &str) {
+fn set_macos_config_url(group_id: &str, config: &RemoteConfig) -> InstallMode {
+    let kind = config.kind();
+    let Some(config_url) = config.fallback_url() else {
+        info!("Inline {} could not be installed and has no URL to fall back to", kind.label());
+        return InstallMode::NotInstalled;
+    };
     info!("Setting config URL fallback mode for group {}", group_id);
     
     // Set isInternalUser to true (required for custom config URLs to be used)
     let output = Command::new("defaults")
-        .args(&[
+        .args([
             "write",
             group_id,
             "isInternalUser",
@@ -679,12 +1093,12 @@ fn set_macos_config_url(group_id: &str, config_url: &str) {
         );
     }
     
-    // Set the custom privacy configuration URL
+    // Set the custom configuration URL
     let output = Command::new("defaults")
-        .args(&[
+        .args([
             "write",
             group_id,
-            "CustomConfigurationURL.privacyConfiguration",
+            &format!("CustomConfigurationURL.{}", kind.name()),
             "-string",
             config_url,
         ])
@@ -696,13 +1110,15 @@ fn set_macos_config_url(group_id: &str, config_url: &str) {
             "Failed to set custom config URL: {}",
             String::from_utf8_lossy(&output.stderr)
         );
+        return InstallMode::NotInstalled;
     }
+    InstallMode::Url
 }
 
 fn is_macos_app_running(_bundle_id: &str) -> bool {
     // Check if DuckDuckGo app is running using osascript
     let output = Command::new("osascript")
-        .args(&["-e", "tell application \"System Events\" to (name of processes) contains \"DuckDuckGo\""])
+        .args(["-e", "tell application \"System Events\" to (name of processes) contains \"DuckDuckGo\""])
         .output();
     
     match output {
@@ -714,7 +1130,12 @@ fn is_macos_app_running(_bundle_id: &str) -> bool {
     }
 }
 
-fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Result<(Child, String), String> {
+fn launch_macos_app(
+    app_path: &str,
+    port: u16,
+    ddg_caps: &DdgCapabilities,
+    remote_configs: &[RemoteConfig],
+) -> Result<(Child, String, InstalledConfig), String> {
     info!("Launching macOS app at: {}", app_path);
 
     // Get the bundle ID from the app
@@ -727,7 +1148,7 @@ fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Re
         
         // Try graceful quit via AppleScript
         let _ = Command::new("osascript")
-            .args(&["-e", &format!("tell application id \"{}\" to quit", bundle_id)])
+            .args(["-e", &format!("tell application id \"{}\" to quit", bundle_id)])
             .output();
 
         // Wait and check if it quit
@@ -741,7 +1162,7 @@ fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Re
         if is_macos_app_running(&bundle_id) {
             info!("App still running, sending SIGTERM...");
             let _ = Command::new("pkill")
-                .args(&["-TERM", "-f", &bundle_id])
+                .args(["-TERM", "-f", &bundle_id])
                 .output();
             
             // Wait for graceful shutdown
@@ -756,7 +1177,7 @@ fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Re
         if is_macos_app_running(&bundle_id) {
             info!("App still running, force killing...");
             let _ = Command::new("pkill")
-                .args(&["-KILL", "-f", &bundle_id])
+                .args(["-KILL", "-f", &bundle_id])
                 .output();
             std::thread::sleep(std::time::Duration::from_millis(500));
         }
@@ -766,12 +1187,15 @@ fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Re
     write_macos_defaults(&bundle_id, "automationPort", "int", &port.to_string());
     write_macos_defaults(&bundle_id, "isUITesting", "bool", "true");
     write_macos_defaults(&bundle_id, "isOnboardingCompleted", "string", "true");
-
-    // Set up custom privacy configuration if provided via URL (writes to cache)
-    if let Some(ref config_url) = ddg_caps.privacy_config_url {
-        setup_macos_privacy_config(&bundle_id, config_url);
+    for (flag, enabled) in ddg_caps.feature_flags.iter().flatten() {
+        write_macos_defaults(&bundle_id, &feature_flags::override_key(flag), "bool", &enabled.to_string());
     }
 
+    // Set up custom remote configurations provided via URL or inline (writes to cache)
+    let privacy_config = install_remote_configs(remote_configs, ddg_caps.privacy_config_path.as_deref(), |config| {
+        setup_macos_remote_config(&bundle_id, config)
+    });
+
     // Launch the app
     // Remove CI env var to prevent app from thinking it's in UI test mode
     // (which would cause it to try loading MockEncryptionKeyStore that doesn't exist)
@@ -781,7 +1205,7 @@ fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Re
         let binary_path = format!("{}/Contents/MacOS/DuckDuckGo", app_path);
         info!("Launching binary directly with TEST_PRIVACY_CONFIG_PATH={}", config_path);
         Command::new(&binary_path)
-            .args(&["-isUITesting", "true"])
+            .args(["-isUITesting", "true"])
             .env("TEST_PRIVACY_CONFIG_PATH", config_path)
             .env_remove("CI")
             .spawn()
@@ -789,18 +1213,39 @@ fn launch_macos_app(app_path: &str, port: u16, ddg_caps: &DdgCapabilities) -> Re
     } else {
         // Use standard `open -a` approach
         Command::new("open")
-            .args(&["-a", app_path, "--args", "-isUITesting", "true"])
+            .args(["-a", app_path, "--args", "-isUITesting", "true"])
             .env_remove("CI")
             .spawn()
             .map_err(|e| format!("Failed to launch app: {}", e))?
     };
 
-    Ok((child, bundle_id))
+    Ok((child, bundle_id, privacy_config))
+}
+
+/// Install `remote_configs` with `setup`, reporting what the app was given
+/// as its privacy config
+fn install_remote_configs(
+    remote_configs: &[RemoteConfig],
+    privacy_config_path: Option<&str>,
+    mut setup: impl FnMut(&RemoteConfig) -> InstallMode,
+) -> InstalledConfig {
+    let mut privacy_config = InstalledConfig::bundled();
+    for config in remote_configs {
+        let mode = setup(config);
+        if config.kind() == ConfigKind::PrivacyConfiguration {
+            privacy_config = InstalledConfig::new(config, mode);
+        }
+    }
+    // The app loads the TEST_PRIVACY_CONFIG_PATH file instead of its cache
+    if let Some(config_path) = privacy_config_path {
+        privacy_config = InstalledConfig::from_path(config_path);
+    }
+    privacy_config
 }
 
 fn monitor_macos_logs(bundle_id: &str) -> Child {
     let child = Command::new("log")
-        .args(&[
+        .args([
             "stream",
             "--info",
             "--debug",
@@ -813,7 +1258,7 @@ fn monitor_macos_logs(bundle_id: &str) -> Child {
     child
 }
 
-fn quit_macos_app_with_port(port: Option<u16>) {
+fn quit_macos_app(port: u16, bundle_id: &str) {
     info!("Quitting macOS app gracefully via /shutdown endpoint...");
     
     // Call the /shutdown endpoint which cleanly closes the automation server
@@ -822,26 +1267,14 @@ fn quit_macos_app_with_port(port: Option<u16>) {
         .timeout(std::time::Duration::from_secs(3))
         .build();
     
-    // If we have the actual port, use it; otherwise try common ports as fallback
-    let ports_to_try: Vec<u16> = match port {
-        Some(p) => vec![p],
-        None => (8557..=8570).collect(), // Try a range of ports as fallback
-    };
-    
     if let Ok(client) = client {
-        for port in ports_to_try {
-            let url = format!("http://localhost:{}/shutdown", port);
-            info!("Trying shutdown on port {}...", port);
-            match client.get(&url).send() {
-                Ok(response) => {
-                    info!("Shutdown response on port {}: {:?}", port, response.status());
-                    if response.status().is_success() {
-                        break;
-                    }
-                },
-                Err(e) => {
-                    info!("Shutdown on port {} failed: {}", port, e);
-                }
+        let url = format!("http://localhost:{}/shutdown", port);
+        match client.get(&url).send() {
+            Ok(response) => {
+                info!("Shutdown response on port {}: {:?}", port, response.status());
+            },
+            Err(e) => {
+                info!("Shutdown on port {} failed: {}", port, e);
             }
         }
     }
@@ -851,7 +1284,7 @@ fn quit_macos_app_with_port(port: Option<u16>) {
     
     // Verify it's not running
     for _ in 0..10 {
-        if !is_macos_app_running("com.duckduckgo.macos.browser") {
+        if !is_macos_app_running(bundle_id) {
             info!("macOS app terminated cleanly");
             return;
         }
@@ -861,12 +1294,12 @@ fn quit_macos_app_with_port(port: Option<u16>) {
     // Fallback: if /shutdown didn't work, try AppleScript
     info!("App still running, trying AppleScript quit...");
     let _ = Command::new("osascript")
-        .args(&["-e", "tell application \"DuckDuckGo\" to quit"])
+        .args(["-e", "tell application \"DuckDuckGo\" to quit"])
         .output();
     
     std::thread::sleep(std::time::Duration::from_millis(1000));
     
-    if !is_macos_app_running("com.duckduckgo.macos.browser") {
+    if !is_macos_app_running(bundle_id) {
         info!("macOS app quit via AppleScript");
         return;
     }
@@ -874,83 +1307,36 @@ fn quit_macos_app_with_port(port: Option<u16>) {
     // Last resort - SIGTERM
     info!("App not responding, sending SIGTERM...");
     let _ = Command::new("pkill")
-        .args(&["-TERM", "-f", "DuckDuckGo.app"])
+        .args(["-TERM", "-f", "DuckDuckGo.app"])
         .output();
 }
 
-fn server_request_for_platform(session_id: &str, platform: &Platform, method: &str, params: &std::collections::HashMap<&str, &str>) -> String {
-    match platform {
-        Platform::IOS => {
-            // iOS uses simulator logs
-            let mut child = monitor_simulator_logs(&session_id);
-            let stdout = child.stdout.take().expect("Failed to capture stdout");
-            thread::spawn(move || {
-                let reader = BufReader::new(stdout);
-                info!("Simulator logs:");
-                for line in reader.lines() {
-                    if let Ok(log_line) = line {
-                        info!("{}", log_line);
-                    }
-                }
-                info!("Simulator logs end");
-            });
-            let port = get_port(session_id);
-            let result = make_server_request(port, method, params);
-            let _ = child.kill();
-            result
-        },
-        Platform::MacOS => {
-            // macOS uses direct log stream
-            let mut child = monitor_macos_logs(APP_BUNDLE_ID_MACOS);
-            let stdout = child.stdout.take().expect("Failed to capture stdout");
-            thread::spawn(move || {
-                let reader = BufReader::new(stdout);
-                info!("macOS app logs:");
-                for line in reader.lines() {
-                    if let Ok(log_line) = line {
-                        info!("{}", log_line);
-                    }
-                }
-                info!("macOS app logs end");
-            });
-            let port = get_port(session_id);
-            let result = make_server_request(port, method, params);
-            let _ = child.kill();
-            result
+/// Forward a log stream child's output to the driver log on a background thread
+fn forward_logs(child: &mut Child, source: &'static str) {
+    let stdout = child.stdout.take().expect("Failed to capture stdout");
+    thread::spawn(move || {
+        let reader = BufReader::new(stdout);
+        info!("{} logs:", source);
+        for log_line in reader.lines().map_while(Result::ok) {
+            info!("{}", log_line);
         }
-    }
+        info!("{} logs end", source);
+    });
 }
 
-fn make_server_request(port: u16, method: &str, params: &std::collections::HashMap<&str, &str>) -> String {
-    let query_string: String = params.iter()
-        .map(|(key, value)| format!("{}={}", key, value))
-        .collect::<Vec<String>>()
-        .join("&");
-    let url = format!("http://localhost:{}/{method}?{}", port, query_string);
-    info!("URL to send: {:?}", url);
-    let client = reqwest::blocking::Client::new();
-    let resp = client.get(url)
-        .timeout(std::time::Duration::from_secs(30))
-        .send()
-        .map_err(|e| {
-            if e.is_timeout() {
-                info!("Request timed out");
-                "Request timed out".to_string()
-            } else {
-                format!("Request error: {}", e)
-            }
-        })
-        .expect("Failed to send request")
-        .text()
-        .expect("Failed to read response text");
-    info!("Response: {:#?}", resp);
+/// Stop a log stream child and reap it
+fn stop_logs(mut child: Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
 
-    #[derive(Deserialize)]
-    struct Response {
-        message: String,
-    }
-    let json: Response = serde_json::from_str(&resp).expect("Failed to parse response");
-    return json.message;
+/// Give up on starting an iOS session: stop its log stream and remove the
+/// extracted app. The slot gives the port and simulator back when dropped.
+fn abandon_ios_launch(child: Child, app: &AppBundle, message: String) -> WebDriverError {
+    info!("{}", message);
+    stop_logs(child);
+    app.remove_extracted();
+    WebDriverError::new(ErrorStatus::SessionNotCreated, message)
 }
 
 // iOS-specific constants (kept for backward compatibility)
@@ -970,7 +1356,7 @@ show
 
 */
     let child = Command::new("xcrun")
-        .args(&[
+        .args([
             "simctl",
             "spawn",
             udid,
@@ -1000,12 +1386,19 @@ show
         }
     });
     */
-    return child;
+    child
+}
+
+pub(crate) fn xcrun_command(args: &[&str]) -> std::process::Output {
+    xcrun_command_with_env(args, &[])
 }
 
-fn xcrun_command(args: &[&str]) -> std::process::Output {
+/// Run xcrun with extra environment variables, e.g. `SIMCTL_CHILD_*` ones
+/// that `simctl launch` passes on to the app
+fn xcrun_command_with_env(args: &[&str], envs: &[(&str, &str)]) -> std::process::Output {
     let output = Command::new("xcrun")
         .args(args)
+        .envs(envs.iter().copied())
         .output()
         .expect("Failed to run xcrun command");
     if !output.status.success() {
@@ -1023,7 +1416,7 @@ fn xcrun_command(args: &[&str]) -> std::process::Output {
     println!("stdout: {}", stdout_str);
     println!("stderr: {}", stderr_str);
     */
-    return output;
+    output
 }
 
 fn write_defaults(udid: &str, key: &str, key_type: &str, value: &str) {
@@ -1066,7 +1459,7 @@ fn write_ios_app_config_defaults(udid: &str, key: &str, key_type: &str, value: &
 fn get_ios_simulator_group_container(udid: &str, group_id: &str) -> Option<PathBuf> {
     // Use simctl to get the container path
     let output = Command::new("xcrun")
-        .args(&[
+        .args([
             "simctl",
             "get_app_container",
             udid,
@@ -1093,20 +1486,41 @@ fn get_ios_simulator_group_container(udid: &str, group_id: &str) -> Option<PathB
     }
 }
 
-/// Set up custom privacy configuration for iOS simulator
+/// Copy a `ddg:privacyConfigPath` file into the app's data container so the
+/// app can read it from inside the simulator, returning the path to pass as
+/// `TEST_PRIVACY_CONFIG_PATH`. Falls back to the host path if copying fails.
+fn stage_ios_privacy_config_path(udid: &str, config_path: &str) -> String {
+    let container = xcrun_command(&["simctl", "get_app_container", udid, APP_BUNDLE_ID, "data"]);
+    if container.status.success() {
+        let staged = PathBuf::from(String::from_utf8_lossy(&container.stdout).trim())
+            .join("tmp")
+            .join("webdriver-privacy-config.json");
+        let copied = std::fs::create_dir_all(staged.parent().unwrap()).and_then(|_| std::fs::copy(config_path, &staged));
+        match copied {
+            Ok(_) => {
+                info!("Copied {} into the simulator at {:?}", config_path, staged);
+                return staged.to_string_lossy().into_owned();
+            }
+            Err(e) => warn!("Failed to copy {} into the simulator, passing the host path: {}", config_path, e),
+        }
+    }
+    config_path.to_string()
+}
+
+/// Set up a custom remote configuration for iOS simulator
 /// This pre-fetches the config and writes it directly to the app's cache
-fn setup_ios_privacy_config(udid: &str, config_url: &str) {
-    info!("Setting up custom privacy config for iOS simulator {}", udid);
-    info!("  Config URL: {}", config_url);
+fn setup_ios_remote_config(udid: &str, config: &RemoteConfig) -> InstallMode {
+    let kind = config.kind();
+    info!("Setting up custom {} for iOS simulator {}", kind.label(), udid);
+    info!("  Config: {}", config.fallback_url().unwrap_or("inline"));
     
     // Fetch the config data
-    let config_data = match fetch_privacy_config(config_url) {
+    let config_data = match config.data() {
         Ok(data) => data,
         Err(e) => {
-            info!("Failed to fetch privacy config: {}", e);
+            info!("Failed to fetch {}: {}", kind.label(), e);
             // Fall back to just setting the URL
-            set_ios_config_url_fallback(udid, config_url);
-            return;
+            return set_ios_config_url_fallback(udid, config);
         }
     };
     
@@ -1117,21 +1531,20 @@ fn setup_ios_privacy_config(udid: &str, config_url: &str) {
     if let Some(container) = container_path {
         info!("Found group container: {:?}", container);
         
-        // Write the config file (iOS uses "privacyConfiguration")
-        let config_file = container.join("privacyConfiguration");
+        // Write the config file (iOS names it after the config, e.g. "privacyConfiguration")
+        let config_file = container.join(kind.name());
         info!("Writing config to: {:?}", config_file);
         
         // Use simctl to write the file
-        let temp_file = std::env::temp_dir().join(format!("privacyConfig-{}.json", udid));
+        let temp_file = std::env::temp_dir().join(format!("{}-{}", kind.name(), udid));
         if let Err(e) = std::fs::write(&temp_file, &config_data) {
             info!("Failed to write temp file: {}", e);
-            set_ios_config_url_fallback(udid, config_url);
-            return;
+            return set_ios_config_url_fallback(udid, config);
         }
         
         // Copy to simulator using simctl
         let result = Command::new("xcrun")
-            .args(&[
+            .args([
                 "simctl",
                 "spawn",
                 udid,
@@ -1149,11 +1562,6 @@ fn setup_ios_privacy_config(udid: &str, config_url: &str) {
                 info!("Successfully wrote config file to simulator");
                 
                 // Set etag in the content blocker group defaults
-                let etag = format!("webdriver-{}", std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap_or_default()
-                    .as_secs());
-                
                 // The etag key for iOS (from UserDefaultsETagStorage)
                 xcrun_command(&[
                     "simctl",
@@ -1162,13 +1570,13 @@ fn setup_ios_privacy_config(udid: &str, config_url: &str) {
                     "defaults",
                     "write",
                     IOS_CONTENT_BLOCKER_GROUP,
-                    "com.duckduckgo.ios.etag.privacyConfiguration",
+                    &format!("com.duckduckgo.ios.etag.{}", kind.name()),
                     "-string",
-                    &etag,
+                    &content_etag(&config_data),
                 ]);
                 
-                info!("Successfully pre-cached privacy config ({} bytes)", config_data.len());
-                return;
+                info!("Successfully pre-cached {} ({} bytes)", kind.label(), config_data.len());
+                return InstallMode::CacheWrite;
             },
             _ => {
                 info!("Failed to copy config to simulator, falling back to URL mode");
@@ -1179,98 +1587,291 @@ fn setup_ios_privacy_config(udid: &str, config_url: &str) {
     }
     
     // Fall back to setting the URL
-    set_ios_config_url_fallback(udid, config_url);
+    set_ios_config_url_fallback(udid, config)
 }
 
 /// Fallback: Set the custom config URL for iOS (used if pre-fetching fails)
-fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
+fn set_ios_config_url_fallback(udid: &str, config: &RemoteConfig) -> InstallMode {
+    let kind = config.kind();
+    let Some(config_url) = config.fallback_url() else {
+        info!("Inline {} could not be installed and has no URL to fall back to", kind.label());
+        return InstallMode::NotInstalled;
+    };
     info!("Using URL fallback mode for iOS");
     
     // Set isInternalUser to true in the app config group
     write_ios_app_config_defaults(udid, "isInternalUser", "bool", "true");
     
-    // Set the custom privacy configuration URL
-    write_ios_app_config_defaults(udid, "CustomConfigurationURL.privacyConfiguration", "string", config_url);
+    // Set the custom configuration URL
+    write_ios_app_config_defaults(udid, &format!("CustomConfigurationURL.{}", kind.name()), "string", config_url);
+    InstallMode::Url
 }
 
- impl WebDriverHandler<DuckDuckGoExtensionRoute> for Handler {
-     fn handle_command(
-         &mut self,
-         _: &Option<Session>,
-         msg: WebDriverMessage<DuckDuckGoExtensionRoute>,
-     ) -> WebDriverResult<WebDriverResponse> {
+/// Install a new privacy config into a session's running app and wait for
+/// the content blocker rules compiled from it, returning how long that took
+fn change_privacy_config(
+    session: &DriverSession,
+    requested: &DdgCapabilities,
+    cache: &ConfigCache,
+) -> WebDriverResult<std::time::Duration> {
+    // The app keeps reading TEST_PRIVACY_CONFIG_PATH, not the cache a change writes to
+    if session.ddg_caps.lock().unwrap().privacy_config_path.is_some() {
+        return Err(WebDriverError::new(
+            ErrorStatus::UnsupportedOperation,
+            "The privacy config of a session started with ddg:privacyConfigPath can't be changed",
+        ));
+    }
+    let configs = RemoteConfig::from_capabilities(requested, cache)?;
+    let config = configs.first().expect("A privacy config change resolves to a privacy config");
+    info!("Changing the privacy config of the app on port {}", session.port);
+    let mode = match session.platform {
+        Platform::MacOS => setup_macos_remote_config(&session.bundle_id, config),
+        Platform::IOS => {
+            let udid = session.simulator_udid.as_deref().expect("iOS sessions run on a simulator");
+            setup_ios_remote_config(udid, config)
+        }
+    };
+    let installed = InstalledConfig::new(config, mode);
+    // An identical config keeps its etag, so the app has nothing to recompile
+    let unchanged = installed.content_hash.is_some()
+        && installed.content_hash == session.privacy_config.lock().unwrap().content_hash;
+    // The app reloads the config from its cache (or the URL it was pointed at)
+    // and reports the content blocker as not ready until the new rules are compiled
+    let params = HashMap::from([("name", config.kind().name())]);
+    session.request("reloadConfiguration", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
+    let ready = if unchanged {
+        info!("Privacy config is unchanged, not waiting for recompiled rules");
+        std::time::Duration::ZERO
+    } else {
+        wait_for_rules_reload(session.port, "the privacy config change")?
+    };
+    let mut ddg_caps = session.ddg_caps.lock().unwrap();
+    ddg_caps.privacy_config_url = requested.privacy_config_url.clone();
+    ddg_caps.privacy_config = requested.privacy_config.clone();
+    ddg_caps.privacy_config_patch = requested.privacy_config_patch.clone();
+    ddg_caps.privacy_config_validation = requested.privacy_config_validation;
+    *session.privacy_config.lock().unwrap() = installed;
+    Ok(ready)
+}
 
-        let platform = Platform::from_env();
-        info!("Target platform: {:?}", platform);
+/// Add or remove a domain from the app's protection allowlist and wait for
+/// the recompiled rules, returning the protection state of the current tab
+fn toggle_protection(session: &DriverSession, parameters: &DuckDuckGoProtectionParameters) -> WebDriverResult<Value> {
+    let domain = match parameters.domain {
+        Some(ref domain) => domain.clone(),
+        None => {
+            let current = session.request("getUrl", &HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
+            url::Url::parse(&current)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from))
+                .ok_or_else(|| {
+                    WebDriverError::new(
+                        ErrorStatus::InvalidArgument,
+                        format!("The current tab ({}) has no domain to toggle protection for", current),
+                    )
+                })?
+        }
+    };
+    let method = if parameters.protected { "removeFromAllowlist" } else { "addToAllowlist" };
+    info!("Protection for {} on port {}: {}", domain, session.port, method);
+    session.request(method, &HashMap::from([("domain", domain.as_str())]), Some(DEFAULT_REQUEST_TIMEOUT))?;
+    let ready = wait_for_rules_reload(session.port, &format!("toggling protection for {}", domain))?;
+    let state = session.request("getProtectionState", &HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
+    let mut state: Value = parse_message("getProtectionState", &state)?;
+    if let Some(state) = state.as_object_mut() {
+        state.insert("contentBlockerReadyMs".to_string(), (ready.as_millis() as u64).into());
+    }
+    Ok(state)
+}
 
-        let target_device = if let Ok(env_path) = std::env::var("TARGET_DEVICE") {
-            env_path
-        } else {
-            "iPhone-16".to_string()
-        };
-        let target_os = if let Ok(env_path) = std::env::var("TARGET_OS") {
-            env_path
-        } else {
-            "iOS-18-2".to_string()
-        };
+/// Wait for the content blocker to go from not ready to ready. Right after a
+/// reload the app can still report the previous rules as ready, so ready only
+/// counts once it has been seen recompiling.
+fn wait_for_rules_reload(port: u16, change: &str) -> WebDriverResult<std::time::Duration> {
+    let start = Instant::now();
+    let mut recompiling = false;
+    loop {
+        match content_blocker_ready(port) {
+            Some(false) if !recompiling => {
+                info!("Content blocker recompiling after {:?}", start.elapsed());
+                recompiling = true;
+            }
+            Some(true) if recompiling => {
+                info!("Content blocker ready after {:?}", start.elapsed());
+                return Ok(start.elapsed());
+            }
+            _ => {}
+        }
+        if start.elapsed() >= CONTENT_BLOCKER_TIMEOUT {
+            let state = if recompiling { "report ready" } else { "start recompiling" };
+            return Err(WebDriverError::new(
+                ErrorStatus::Timeout,
+                format!("Content blocker did not {} after {}", state, change),
+            ));
+        }
+        // Polled often, so a quick recompile isn't missed
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// How long the content blocker may take to compile its rules
+const CONTENT_BLOCKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether the app reports its content blocker rules as compiled, or `None`
+/// if it couldn't be asked
+fn content_blocker_ready(port: u16) -> Option<bool> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(std::time::Duration::from_millis(500))
+        .build()
+        .expect("Failed to create client");
+    let text = client
+        .get(format!("http://localhost:{}/contentBlockerReady", port))
+        .send()
+        .and_then(|response| response.text())
+        .map_err(|e| info!("Content blocker check failed: {}", e))
+        .ok()?;
+    // { "message": "true"|"false", "requestPath": "/contentBlockerReady" }
+    let json: Value = serde_json::from_str(&text)
+        .map_err(|_| info!("Content blocker check: failed to parse JSON: {}", text))
+        .ok()?;
+    json.get("message").and_then(|v| v.as_str()).map(|message| message == "true")
+}
+
+/// Wait for the content blocker rules to be compiled, so the browser is fully
+/// ready before WebDriver considers the session started. Returns how long that
+/// took, or `None` if the app did not report ready within 30 seconds.
+fn wait_for_content_blocker(port: u16) -> Option<std::time::Duration> {
+    info!("Waiting for content blocker to be ready...");
+    let cb_start = std::time::Instant::now();
+    let mut cb_attempts = 0;
+    loop {
+        if content_blocker_ready(port) == Some(true) {
+            let elapsed = cb_start.elapsed();
+            info!("Content blocker ready after {:?} ({} attempts)", elapsed, cb_attempts + 1);
+            return Some(elapsed);
+        }
+        info!("Content blocker not ready yet (attempt {})", cb_attempts + 1);
+        cb_attempts += 1;
+        if cb_start.elapsed() >= CONTENT_BLOCKER_TIMEOUT {
+            info!("Warning: Timeout waiting for content blocker after {:?}, proceeding anyway", cb_start.elapsed());
+            return None;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(500));
+    }
+}
+
+ impl Handler {
+     pub fn handle_command(
+         &self,
+         msg: WebDriverMessage<DuckDuckGoExtensionRoute>,
+     ) -> WebDriverResult<WebDriverResponse> {
 
         info!("Message received {:?}", msg);
-        return match msg.command {
+        // A running command keeps its session from being ended as idle
+        let _command = msg
+            .session_id
+            .as_ref()
+            .and_then(|session_id| self.sessions.lock().unwrap().get(session_id).cloned())
+            .map(|session| session.begin_command());
+        match msg.command {
             WebDriverCommand::NewSession(ref params) => {
-                // Parse DuckDuckGo-specific capabilities from the session parameters
-                let ddg_caps = DdgCapabilities::from_new_session_params(params);
+                // Merge and validate the requested capabilities before starting anything
+                let mut browser = DuckDuckGoCapabilities::new(Platform::from_env());
+                let merged = browser.match_session(params)?;
+                let platform = browser.platform(&merged);
+                info!("Target platform: {:?}", platform);
+                let mut ddg_caps = DdgCapabilities::from_capabilities(&merged)?;
+                let mut timeouts = Timeouts::default();
+                if let Some(ref requested) = requested_timeouts(&merged)? {
+                    timeouts.update(requested);
+                }
+                // Fetched and patched up front so bad configs fail before anything launches
+                let remote_configs = RemoteConfig::from_capabilities(&ddg_caps, &self.config_cache)?;
+                // The app doesn't share the driver's working directory
+                if let Some(ref config_path) = ddg_caps.privacy_config_path {
+                    let resolved = std::fs::canonicalize(config_path)
+                        .ok()
+                        .filter(|path| path.is_file())
+                        .ok_or_else(|| {
+                            WebDriverError::new(
+                                ErrorStatus::InvalidArgument,
+                                format!("ddg:privacyConfigPath {} is not a file", config_path),
+                            )
+                        })?;
+                    ddg_caps.privacy_config_path = Some(resolved.to_string_lossy().into_owned());
+                }
+                let app = AppBundle::resolve(platform, &ddg_caps)?;
+                let app_path = app.path.to_str().expect("Failed to convert path to string").to_string();
+                // ddg:reuseApp sessions attach to a kept app or skip reinstalling only if the bundle is unchanged
+                let app_hash = if ddg_caps.reuse_app == Some(true) {
+                    match content_hash(&app.path) {
+                        Ok(hash) => {
+                            info!("App content hash: {}", hash);
+                            Some(hash)
+                        }
+                        Err(e) => {
+                            app.remove_extracted();
+                            return Err(WebDriverError::new(
+                                ErrorStatus::SessionNotCreated,
+                                format!("Failed to read the app bundle {}: {}", app_path, e),
+                            ));
+                        }
+                    }
+                } else {
+                    None
+                };
+                let mut slot = match self.reserve_session(platform) {
+                    Ok(slot) => slot,
+                    Err(e) => {
+                        app.remove_extracted();
+                        return Err(e);
+                    }
+                };
                 
                 match platform {
                     Platform::MacOS => {
                         info!("Starting macOS automation...");
-                        
-                        // Generate a unique session ID for macOS
-                        let session_id = Uuid::new_v4().to_string();
-                        
-                        // Get app path from environment or use default
-                        let app_path = if let Ok(env_path) = std::env::var("MACOS_APP_PATH") {
-                            env_path
-                        } else {
-                            let derived_data_path = if let Ok(env_path) = std::env::var("DERIVED_DATA_PATH") {
-                                PathBuf::from(env_path)
-                            } else {
-                                let current_dir = std::env::current_dir().expect("Failed to get current directory");
-                                // apple-browsers DerivedData is in the ddg-workflow monorepo
-                                current_dir.join("../ddg-workflow/apple-browsers/DerivedData")
-                            };
-                            format!("{}/Build/Products/Debug/DuckDuckGo.app", derived_data_path.to_str().expect("Failed to convert path to string"))
-                        };
+
+                        // A kept app is either attached to or quit so a fresh instance can launch
+                        if let Some(kept) = self.take_kept_app(platform, None) {
+                            match app_hash {
+                                Some(ref hash) if kept.is_attachable(hash, &ddg_caps) && reset_kept_app(&kept) => {
+                                    return self.attach_kept_app(slot, kept, app, ddg_caps, timeouts);
+                                }
+                                _ => self.discard_kept_app(kept),
+                            }
+                        }
                         
                         info!("macOS App Path: {:?}", app_path);
                         
                         // Get port for this session
-                        let port = get_port(&session_id);
+                        let port = match slot.allocate_port() {
+                            Ok(port) => port,
+                            Err(e) => {
+                                app.remove_extracted();
+                                return Err(e);
+                            }
+                        };
                         
                         // Launch the macOS app with DuckDuckGo capabilities
-                        let bundle_id = match launch_macos_app(&app_path, port, &ddg_caps) {
-                            Ok((_, bundle_id)) => {
+                        let (bundle_id, privacy_config) = match launch_macos_app(&app_path, port, &ddg_caps, &remote_configs) {
+                            Ok((_, bundle_id, privacy_config)) => {
                                 info!("Launched macOS app");
-                                bundle_id
+                                (bundle_id, privacy_config)
                             },
                             Err(e) => {
                                 info!("Failed to launch macOS app: {}", e);
-                                return Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)));
+                                app.remove_extracted();
+                                return Err(WebDriverError::new(
+                                    ErrorStatus::SessionNotCreated,
+                                    format!("Failed to launch macOS app: {}", e),
+                                ));
                             }
                         };
                         
                         // Start monitoring logs with correct bundle ID
                         let mut child = monitor_macos_logs(&bundle_id);
-                        let stdout = child.stdout.take().expect("Failed to capture stdout");
-                        thread::spawn(move || {
-                            let reader = BufReader::new(stdout);
-                            info!("macOS app logs:");
-                            for line in reader.lines() {
-                                if let Ok(log_line) = line {
-                                    info!("{}", log_line);
-                                }
-                            }
-                            info!("macOS app logs end");
-                        });
+                        forward_logs(&mut child, "macOS app");
                         
                         // Wait for the server to start by testing connectivity
                         info!("Waiting for automation server on port {}...", port);
@@ -1292,107 +1893,110 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                             }
                             attempts += 1;
                             if attempts > 120 { // 60 seconds timeout
-                                panic!("Timeout waiting for automation server to start");
+                                stop_logs(child);
+                                app.remove_extracted();
+                                return Err(WebDriverError::new(
+                                    ErrorStatus::SessionNotCreated,
+                                    format!("Timeout waiting for automation server to start on port {}", port),
+                                ));
                             }
                             std::thread::sleep(std::time::Duration::from_millis(500));
                         }
                         
-                        // Wait for content blocker rules to be compiled
-                        // This ensures the browser is fully ready before WebDriver considers the session started
-                        info!("Waiting for content blocker to be ready...");
-                        let cb_start = std::time::Instant::now();
-                        let mut cb_attempts = 0;
-                        loop {
-                            let client = reqwest::blocking::Client::builder()
-                                .timeout(std::time::Duration::from_millis(500))
-                                .build()
-                                .expect("Failed to create client");
-                            match client.get(format!("http://localhost:{}/contentBlockerReady", port)).send() {
-                                Ok(response) => {
-                                    if let Ok(text) = response.text() {
-                                        // Parse JSON: { "message": "true"|"false", "requestPath": "/contentBlockerReady" }
-                                        if let Ok(json) = serde_json::from_str::<Value>(&text) {
-                                            if let Some(message) = json.get("message").and_then(|v| v.as_str()) {
-                                                if message == "true" {
-                                                    let elapsed = cb_start.elapsed();
-                                                    info!("Content blocker ready after {:?} ({} attempts)", elapsed, cb_attempts + 1);
-                                                    break;
-                                                }
-                                            }
-                                            info!("Content blocker not ready yet (attempt {}, message: {:?})", cb_attempts + 1, json.get("message"));
-                                        } else {
-                                            info!("Content blocker check: failed to parse JSON (attempt {}): {}", cb_attempts + 1, text);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    info!("Content blocker check failed (attempt {}): {}", cb_attempts + 1, e);
-                                }
-                            }
-                            cb_attempts += 1;
-                            if cb_attempts > 60 { // 30 seconds timeout for content blocker
-                                info!("Warning: Timeout waiting for content blocker after {:?}, proceeding anyway", cb_start.elapsed());
-                                break;
-                            }
-                            std::thread::sleep(std::time::Duration::from_millis(500));
-                        }
+                        negotiate_request_format(port);
+
+                        let content_blocker_ready = wait_for_content_blocker(port);
                         
-                        let _ = child.kill();
-                        let capabilities = Map::new();
-                        Ok(WebDriverResponse::NewSession(NewSessionResponse {
-                            session_id: session_id,
-                            capabilities: Value::Object(capabilities),
-                        }))
+                        let session = DriverSession {
+                            platform,
+                            port,
+                            simulator_udid: None,
+                            device: None,
+                            bundle_id,
+                            app_path,
+                            extracted_app: app.extracted_to,
+                            ddg_caps: Mutex::new(ddg_caps),
+                            privacy_config: Mutex::new(privacy_config),
+                            context: Mutex::new(DuckDuckGoContext::default()),
+                            app_hash,
+                            log_stream: Mutex::new(Some(child)),
+                            capabilities: Map::new(),
+                            timeouts: Mutex::new(timeouts),
+                            last_activity: Mutex::new(Instant::now()),
+                            active_commands: AtomicUsize::new(0),
+                        };
+                        Ok(self.register_session(slot, session, get_macos_version(), content_blocker_ready))
                     },
                     Platform::IOS => {
+                        let target_device = ddg_caps.device_type();
+                        let target_os = ddg_caps.os_runtime();
                         info!("Starting iOS automation... {:?} {:?}", target_device, target_os);
-                        let simulator_udid = match find_or_create_simulator(&target_device, &target_os) {
-                            Ok(udid) => udid,
-                            Err(e) => {
-                                info!("Failed to find or create simulator: {}", e);
-                                return Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)));
+                        // A kept app is attached to, or its simulator reused to relaunch or reinstall the app
+                        let mut adopted_udid = None;
+                        if let Some(ref hash) = app_hash {
+                            if let Some(kept) = self.take_kept_app(platform, Some((&target_device, &target_os))) {
+                                if kept.is_attachable(hash, &ddg_caps) && reset_kept_app(&kept) {
+                                    return self.attach_kept_app(slot, kept, app, ddg_caps, timeouts);
+                                }
+                                adopted_udid = Some(self.adopt_simulator(&mut slot, kept));
                             }
+                        }
+                        // Pool simulators are reset when handed back, so they can't keep an app running
+                        let leased_udid = match (&adopted_udid, &app_hash) {
+                            (None, None) => slot.lease_simulator(&target_device, &target_os, &app_path),
+                            _ => None,
+                        };
+                        let simulator_udid = match adopted_udid.clone().or(leased_udid.clone()) {
+                            Some(udid) => udid,
+                            None => match slot.select_simulator(&target_device, &target_os) {
+                                Ok(udid) => udid,
+                                Err(e) => {
+                                    info!("Failed to find or create simulator: {}", e);
+                                    app.remove_extracted();
+                                    return Err(WebDriverError::new(ErrorStatus::SessionNotCreated, e));
+                                }
+                            },
                         };
                         info!("Simulator UDID: {:?}", simulator_udid);
-                    
-                        // Boot the simulator (if it's not already booted)
-                        xcrun_command(&["simctl", "boot", &simulator_udid]);
-                    
+
                         // Launch the simulator app
                         Command::new("open")
-                            .args(&["-a", "Simulator"])
+                            .args(["-a", "Simulator"])
                             .status()
                             .expect("Failed to open the Simulator app");
                         info!("Opened Simulator app");
-                        xcrun_command(&["simctl", "terminate", &simulator_udid, APP_BUNDLE_ID]);
-                        xcrun_command(&["simctl", "uninstall", &simulator_udid, APP_BUNDLE_ID]);
-                        info!("Uninstalled app");
-                        // Install the app on the simulator
-                        let derived_data_path = if let Ok(env_path) = std::env::var("DERIVED_DATA_PATH") {
-                            PathBuf::from(env_path)
+                        let installed_hash = self.installed_apps.lock().unwrap().get(&simulator_udid).cloned();
+                        let reinstall_skipped = app_hash.is_some() && installed_hash == app_hash;
+                        if leased_udid.is_some() {
+                            // Pool simulators are booted clones of the golden device, which already has the app
+                            info!("Using pool simulator with the app installed");
                         } else {
-                            let current_dir = std::env::current_dir().expect("Failed to get current directory");
-                            current_dir.join("../DerivedData")
-                        };
-                        let derived_data_path = derived_data_path.to_str().expect("Failed to convert path to string");
-                        let app_path = format!("{derived_data_path}/Build/Products/Debug-iphonesimulator/DuckDuckGo.app");
-                        info!("App Path: {:?}", app_path);
-                        if !xcrun_command(&["simctl", "install", &simulator_udid, app_path.as_str()]).status.success() {
-                            panic!("Failed to install the app");
-                        }
-                        info!("Installed app");
-                        let mut child = monitor_simulator_logs(&simulator_udid);
-                        let stdout = child.stdout.take().expect("Failed to capture stdout");
-                        thread::spawn(move || {
-                            let reader = BufReader::new(stdout);
-                            info!("Simulator logs:");
-                            for line in reader.lines() {
-                                if let Ok(log_line) = line {
-                                    info!("{}", log_line);
+                            // Boot the simulator (if it's not already booted)
+                            xcrun_command(&["simctl", "boot", &simulator_udid]);
+                            xcrun_command(&["simctl", "terminate", &simulator_udid, APP_BUNDLE_ID]);
+                            if reinstall_skipped {
+                                info!("App bundle unchanged, keeping the installed app");
+                            } else {
+                                xcrun_command(&["simctl", "uninstall", &simulator_udid, APP_BUNDLE_ID]);
+                                self.installed_apps.lock().unwrap().remove(&simulator_udid);
+                                info!("Uninstalled app");
+                                // Install the app on the simulator
+                                let install = xcrun_command(&["simctl", "install", &simulator_udid, app_path.as_str()]);
+                                if !install.status.success() {
+                                    app.remove_extracted();
+                                    return Err(WebDriverError::new(
+                                        ErrorStatus::SessionNotCreated,
+                                        format!("Failed to install the app: {}", String::from_utf8_lossy(&install.stderr).trim()),
+                                    ));
+                                }
+                                info!("Installed app");
+                                if let Some(ref hash) = app_hash {
+                                    self.installed_apps.lock().unwrap().insert(simulator_udid.clone(), hash.clone());
                                 }
                             }
-                            info!("Simulator logs end");
-                        });
+                        }
+                        let mut child = monitor_simulator_logs(&simulator_udid);
+                        forward_logs(&mut child, "Simulator");
                         let logger = xcrun_command(&[
                             "simctl",
                             "spawn",
@@ -1402,10 +2006,14 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                             "--mode",
                             "level:debug",
                             "-subsystem",
-                            &APP_BUNDLE_ID
+                            APP_BUNDLE_ID
                         ]);
                         if !logger.status.success() {
-                            panic!("Failed to set log level\n{}", String::from_utf8_lossy(&logger.stderr));
+                            return Err(abandon_ios_launch(
+                                child,
+                                &app,
+                                format!("Failed to set log level: {}", String::from_utf8_lossy(&logger.stderr).trim()),
+                            ));
                         }
 
                         let persist_logs = xcrun_command(&[
@@ -1417,121 +2025,154 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                             "--mode",
                             "persist:debug",
                             "-subsystem",
-                            &APP_BUNDLE_ID
+                            APP_BUNDLE_ID
                         ]);
                         if !persist_logs.status.success() {
-                            panic!("Failed to perist log level\n{}", String::from_utf8_lossy(&persist_logs.stderr));
+                            return Err(abandon_ios_launch(
+                                child,
+                                &app,
+                                format!("Failed to persist log level: {}", String::from_utf8_lossy(&persist_logs.stderr).trim()),
+                            ));
                         }
 
                         write_defaults(&simulator_udid, "isUITesting", "bool", "true");
                         write_defaults(&simulator_udid, "isOnboardingCompleted", "string", "true");
-                        let port = get_port(&simulator_udid);
+                        let port = match slot.allocate_port() {
+                            Ok(port) => port,
+                            Err(e) => return Err(abandon_ios_launch(child, &app, e.message.to_string())),
+                        };
                         write_defaults(&simulator_udid, "automationPort", "int", port.to_string().as_str());
-
-                        // Set up custom privacy configuration if provided
-                        if let Some(ref config_url) = ddg_caps.privacy_config_url {
-                            setup_ios_privacy_config(&simulator_udid, config_url);
+                        for (flag, enabled) in ddg_caps.feature_flags.iter().flatten() {
+                            write_defaults(&simulator_udid, &feature_flags::override_key(flag), "bool", &enabled.to_string());
                         }
 
-                        if !xcrun_command(&[
+                        // Set up custom remote configurations if provided
+                        let privacy_config = install_remote_configs(
+                            &remote_configs,
+                            ddg_caps.privacy_config_path.as_deref(),
+                            |config| setup_ios_remote_config(&simulator_udid, config),
+                        );
+
+                        // simctl hands SIMCTL_CHILD_* variables to the app without the prefix
+                        let config_path = ddg_caps
+                            .privacy_config_path
+                            .as_deref()
+                            .map(|path| stage_ios_privacy_config_path(&simulator_udid, path));
+                        let mut launch_env = Vec::new();
+                        if let Some(ref config_path) = config_path {
+                            info!("Launching with TEST_PRIVACY_CONFIG_PATH={}", config_path);
+                            launch_env.push(("SIMCTL_CHILD_TEST_PRIVACY_CONFIG_PATH", config_path.as_str()));
+                        }
+                        let launch = xcrun_command_with_env(&[
                                 "simctl",
                                 "launch",
                                 &simulator_udid,
                                 APP_BUNDLE_ID,
                                 "isUITesting",
                                 "true"
-                            ]).status.success() {
-                            panic!("Failed to launch the app");
+                            ], &launch_env);
+                        if !launch.status.success() {
+                            return Err(abandon_ios_launch(
+                                child,
+                                &app,
+                                format!("Failed to launch the app: {}", String::from_utf8_lossy(&launch.stderr).trim()),
+                            ));
                         }
 
-                        // Wait for the server to start
-                        loop {
-                            if !port_is_available(port) {
-                                break;
+                        // Wait for the app to bind its automation port
+                        let mut attempts = 0;
+                        while port_is_available(port) {
+                            attempts += 1;
+                            if attempts > 120 { // 60 seconds timeout
+                                xcrun_command(&["simctl", "terminate", &simulator_udid, APP_BUNDLE_ID]);
+                                return Err(abandon_ios_launch(
+                                    child,
+                                    &app,
+                                    format!("Timeout waiting for automation server to start on port {}", port),
+                                ));
                             }
+                            std::thread::sleep(std::time::Duration::from_millis(500));
                         }
 
-                        // Wait for content blocker rules to be compiled
-                        // This ensures the browser is fully ready before WebDriver considers the session started
-                        info!("Waiting for content blocker to be ready (iOS)...");
-                        let cb_start = std::time::Instant::now();
-                        let mut cb_attempts = 0;
-                        loop {
-                            let client = reqwest::blocking::Client::builder()
-                                .timeout(std::time::Duration::from_millis(500))
-                                .build()
-                                .expect("Failed to create client");
-                            match client.get(format!("http://localhost:{}/contentBlockerReady", port)).send() {
-                                Ok(response) => {
-                                    if let Ok(text) = response.text() {
-                                        // Parse JSON: { "message": "true"|"false", "requestPath": "/contentBlockerReady" }
-                                        if let Ok(json) = serde_json::from_str::<Value>(&text) {
-                                            if let Some(message) = json.get("message").and_then(|v| v.as_str()) {
-                                                if message == "true" {
-                                                    let elapsed = cb_start.elapsed();
-                                                    info!("Content blocker ready (iOS) after {:?} ({} attempts)", elapsed, cb_attempts + 1);
-                                                    break;
-                                                }
-                                            }
-                                            info!("Content blocker not ready yet (iOS, attempt {}, message: {:?})", cb_attempts + 1, json.get("message"));
-                                        } else {
-                                            info!("Content blocker check (iOS): failed to parse JSON (attempt {}): {}", cb_attempts + 1, text);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    info!("Content blocker check failed (iOS, attempt {}): {}", cb_attempts + 1, e);
-                                }
-                            }
-                            cb_attempts += 1;
-                            if cb_attempts > 60 { // 30 seconds timeout for content blocker
-                                info!("Warning: Timeout waiting for content blocker (iOS) after {:?}, proceeding anyway", cb_start.elapsed());
-                                break;
+                        negotiate_request_format(port);
+
+                        // The kept install still has the last session's tabs and data
+                        if reinstall_skipped {
+                            if let Err(e) = reset_app_state(port) {
+                                warn!("Failed to reset the app: {}", e);
                             }
-                            std::thread::sleep(std::time::Duration::from_millis(500));
                         }
 
-                        let _ = child.kill(); // Gracefully kill the child process
-                        let capabilities = Map::new();
-                        Ok(WebDriverResponse::NewSession(NewSessionResponse {
-                            session_id: simulator_udid.to_string(),
-                            capabilities: Value::Object(capabilities),
-                        }))
+                        let content_blocker_ready = wait_for_content_blocker(port);
+
+                        let session = DriverSession {
+                            platform,
+                            port,
+                            simulator_udid: Some(simulator_udid),
+                            device: Some((target_device, target_os.clone())),
+                            bundle_id: platform.bundle_id().to_string(),
+                            app_path,
+                            extracted_app: app.extracted_to,
+                            ddg_caps: Mutex::new(ddg_caps),
+                            privacy_config: Mutex::new(privacy_config),
+                            context: Mutex::new(DuckDuckGoContext::default()),
+                            app_hash,
+                            log_stream: Mutex::new(Some(child)),
+                            capabilities: Map::new(),
+                            timeouts: Mutex::new(timeouts),
+                            last_activity: Mutex::new(Instant::now()),
+                            active_commands: AtomicUsize::new(0),
+                        };
+                        Ok(self.register_session(slot, session, Some(target_os), content_blocker_ready))
                     }
                 }
             },
             DeleteSession => {
                 let session_id = msg.session_id.as_ref().expect("Expected a session id");
                 info!("Deleting session {:?}", session_id);
-                match platform {
-                    Platform::MacOS => {
-                        let port = get_port(session_id);
-                        quit_macos_app_with_port(Some(port));
-                    },
-                    Platform::IOS => {
-                        // Shutdown the simulator
-                        xcrun_command(&["simctl", "shutdown", &session_id]);
-                    }
-                }
-                Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)))
+                self.session(&msg.session_id)?;
+                self.end_session(session_id);
+                Ok(WebDriverResponse::DeleteSession)
+            },
+            SetTimeouts(ref params) => {
+                let session = self.session(&msg.session_id)?;
+                let mut timeouts = session.timeouts.lock().unwrap();
+                timeouts.update(params);
+                info!("Session {:?} timeouts: {:?}", msg.session_id, timeouts);
+                Ok(WebDriverResponse::Void)
+            },
+            GetTimeouts => {
+                let timeouts = self.session(&msg.session_id)?.timeouts();
+                Ok(WebDriverResponse::Timeouts(TimeoutsResponse::new(
+                    timeouts.script,
+                    timeouts.page_load,
+                    timeouts.implicit,
+                )))
             },
             Status => {
-                // W3C WebDriver status endpoint - indicates server readiness
+                // W3C WebDriver status endpoint - indicates whether a new session can start right away
+                let in_use = self.reservations.lock().unwrap().sessions;
+                let ready = in_use < self.max_sessions;
                 let status = serde_json::json!({
-                    "ready": true,
-                    "message": "DuckDuckGo WebDriver ready"
+                    "ready": ready,
+                    "message": if ready { "DuckDuckGo WebDriver ready" } else { "All session slots are in use" },
+                    "sessions": in_use,
+                    "maxSessions": self.max_sessions,
+                    "pool": self.pool.as_ref().map(|pool| pool.status()),
                 });
                 Ok(WebDriverResponse::Generic(ValueResponse(status)))
             },
             Get(params) => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
+                let session = self.session(&msg.session_id)?;
                 let url = params.url.as_str();
                 let mut params = std::collections::HashMap::new();
                 params.insert("url", url);
-                server_request_for_platform(session_id, &platform, "navigate", &params);
-                return Ok(WebDriverResponse::Void);
+                session.request("navigate", &params, session.timeouts().page_load_deadline())?;
+                Ok(WebDriverResponse::Void)
             },
             ExecuteScript(params) => {
+                let session = self.session(&msg.session_id)?;
+                session.require_content_context()?;
                 let script = params.script.as_str();
                 info!("Script: {:#?}", params);
                 let script_args = params.args.as_ref().expect("Expected args");
@@ -1599,11 +2240,9 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                 // Replace SCRIPT and SCRIPT_ARGS with the actual script and arguments
                 let script = script_wrapper.replace("__SCRIPT__", script).replace("__SCRIPT_ARGS__", script_args_str.as_str());
                 let mut params = std::collections::HashMap::new();
-                // Escape the script
-                let script = urlencoding::encode(&script).to_string();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, session.timeouts().script_deadline())
+                    .map_err(script_timeout)?;
                 
                 // Response is the raw message value from the server
                 // It could be:
@@ -1626,9 +2265,11 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                 }
                 
                 // Not valid JSON - treat as plain string
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))))
             },
             ExecuteAsyncScript(params) => {
+                let session = self.session(&msg.session_id)?;
+                session.require_content_context()?;
                 let script = params.script.as_str();
                 info!("Script: {:#?}", params);
                 let script_args = params.args.as_ref().expect("Expected args");
@@ -1646,9 +2287,11 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
 
                 let script_wrapper = r#"
                 let promiseResult = new Promise((res, rej) => {
-                  const timeout = setTimeout(() => {
+                  // Session script timeout in ms, null when scripts may run indefinitely
+                  const scriptTimeout = __SCRIPT_TIMEOUT__;
+                  const timeout = scriptTimeout === null ? null : setTimeout(() => {
                     rej("Script execution timed out");
-                  }, 15000); // 15 secs
+                  }, scriptTimeout);
 
                   (async function asyncMethod () {
                     __SCRIPT__
@@ -1663,56 +2306,60 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                 return promiseResult;
                 "#;
                 // Replace SCRIPT and SCRIPT_ARGS with the actual script and arguments
-                let script = script_wrapper.replace("__SCRIPT__", script).replace("__SCRIPT_ARGS__", script_args_str.as_str());
+                let script_timeout_ms = session.timeouts().script.map_or("null".to_string(), |ms| ms.to_string());
+                let script = script_wrapper
+                    .replace("__SCRIPT__", script)
+                    .replace("__SCRIPT_ARGS__", script_args_str.as_str())
+                    .replace("__SCRIPT_TIMEOUT__", &script_timeout_ms);
                 let mut params = std::collections::HashMap::new();
-                // Escape the script
-                let script = urlencoding::encode(&script).to_string();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, session.timeouts().script_deadline())
+                    .map_err(script_timeout)?;
                 info!("Script Response: {:#?}", response);
-                let parsed: Value = serde_json::from_str(&response)?;
-                return Ok(WebDriverResponse::Generic(ValueResponse(parsed.into())));
+                let parsed: Value = parse_message("execute", &response)?;
+                Ok(WebDriverResponse::Generic(ValueResponse(parsed)))
             },
             FindElement(params) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    let element_id = native_ui::find_element(&session, &params)?;
+                    let mut res = Map::new();
+                    res.insert(webdriver::common::ELEMENT_KEY.to_string(), Value::String(element_id));
+                    return Ok(WebDriverResponse::Generic(ValueResponse(res.into())));
+                }
                 // Read file
-                let script = include_str!("find-element.js");
-                // URL encode the script
-                let script = urlencoding::encode(&script).to_string();
+                // The lookup script polls for up to the session's implicit wait
+                let script = format!("let implicitWait = {};\n{}", session.timeouts().implicit, include_str!("find-element.js"));
                 let mut url_params = std::collections::HashMap::new();
                 url_params.insert("script", script.as_str());
                 let json_string = serde_json::to_string(&params).unwrap();
-                let json_string = urlencoding::encode(&json_string).to_string();
                 url_params.insert("args", json_string.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &url_params);
-                // server_request already extracts the "message" field, so response is the UUID string directly
-                // The response might be a JSON-encoded string, so try parsing it
-                let response_clone = response.clone();
-                let element_id = if let Ok(parsed) = serde_json::from_str::<Value>(&response) {
-                    // If it's a JSON string, extract it
-                    parsed.as_str().unwrap_or(&response).to_string()
-                } else {
-                    // If it's already a plain string, use it directly
-                    response
-                };
-                info!("FindElement response: {:?}, element_id: {:?}", response_clone, element_id);
+                let response = session.request("execute", &url_params, session.timeouts().element_lookup_deadline())?;
+                // A lookup that found nothing after the implicit wait is `no such element`,
+                // which client-side explicit waits retry on
+                let element_id = parse_element_id(&response)?;
+                info!("FindElement response: {:?}, element_id: {:?}", response, element_id);
                 let mut res = Map::new();
                 res.insert(webdriver::common::ELEMENT_KEY.to_string(), Value::String(element_id));
-                return Ok(WebDriverResponse::Generic(ValueResponse(res.into())));
+                Ok(WebDriverResponse::Generic(ValueResponse(res.into())))
             },
             FindElements(params) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    let elements: Vec<Value> = native_ui::find_elements(&session, &params)?
+                        .into_iter()
+                        .map(|id| serde_json::json!({ webdriver::common::ELEMENT_KEY: id }))
+                        .collect();
+                    return Ok(WebDriverResponse::Generic(ValueResponse(elements.into())));
+                }
                 // Read file
-                let script = include_str!("find-elements.js");
-                // URL encode the script
-                let script = urlencoding::encode(&script).to_string();
+                // The lookup script polls for up to the session's implicit wait
+                let script = format!("let implicitWait = {};\n{}", session.timeouts().implicit, include_str!("find-elements.js"));
                 let mut url_params = std::collections::HashMap::new();
                 url_params.insert("script", script.as_str());
                 let json_string = serde_json::to_string(&params).unwrap();
-                let json_string = urlencoding::encode(&json_string).to_string();
                 url_params.insert("args", json_string.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &url_params);
+                let response = session.request("execute", &url_params, session.timeouts().element_lookup_deadline())?;
                 info!("FindElements raw response: {:?} (length: {})", response, response.len());
                 // server_request extracts the "message" field, which contains a JSON array string like "[\"uuid1\",\"uuid2\",...]"
                 // The response is the actual string content (not JSON-encoded), so we parse it directly as JSON
@@ -1767,9 +2414,14 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                         Value::Object(elem)
                     })
                     .collect();
-                return Ok(WebDriverResponse::Generic(ValueResponse(elements.into())));
+                Ok(WebDriverResponse::Generic(ValueResponse(elements.into())))
             },
             ElementClick(element_ref) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    native_ui::element_request(&session, "nativeElementClick", &element_ref.to_string(), &[])?;
+                    return Ok(WebDriverResponse::Void);
+                }
                 let script_body = r#"
                 let element;
                 if (!window.__webdriver_script_results) {
@@ -1793,14 +2445,17 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let elementId = '{}';", &element_ref),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                server_request_for_platform(session_id, &platform, "execute", &params);
-                return Ok(WebDriverResponse::Void);
+                session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
+                Ok(WebDriverResponse::Void)
             },
             GetElementText(element_ref) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    let text = native_ui::element_request(&session, "nativeElementText", &element_ref.to_string(), &[])?;
+                    return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(text))));
+                }
                 let script_body = r#"
                 if (!window.__webdriver_script_results) {
                     return '';
@@ -1816,19 +2471,30 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let elementId = '{}';", &element_ref),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 // Response might be JSON string, extract text
                 let text = serde_json::from_str::<Value>(&response)
                     .ok()
                     .and_then(|v| v.as_str().map(|s| s.to_string()))
                     .unwrap_or(response);
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(text))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(text))))
             },
             GetElementAttribute(element_ref, attr_name) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    let value = native_ui::element_request(
+                        &session,
+                        "nativeElementAttribute",
+                        &element_ref.to_string(),
+                        &[("name", attr_name.as_str())],
+                    )?;
+                    // The automation server sends null as JSON
+                    return Ok(WebDriverResponse::Generic(ValueResponse(
+                        serde_json::from_str::<Value>(&value).unwrap_or(Value::String(value)),
+                    )));
+                }
                 info!("GetElementAttribute called: element={}, attr={}", element_ref, attr_name);
                 let script_body = r#"
                 if (!window.__webdriver_script_results) {
@@ -1846,19 +2512,22 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let attrName = '{}';", &attr_name),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 // Response is the raw attribute value (not JSON-encoded)
                 // If it's "null" string, return null, otherwise return the string
                 if response == "null" || response.is_empty() {
                     return Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)));
                 }
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))))
             },
             IsDisplayed(element_ref) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    let displayed = native_ui::element_request(&session, "nativeElementDisplayed", &element_ref.to_string(), &[])?;
+                    return Ok(WebDriverResponse::Generic(ValueResponse(Value::Bool(displayed == "true"))));
+                }
                 let script_body = r#"
                 if (!window.__webdriver_script_results) {
                     return false;
@@ -1880,16 +2549,19 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let elementId = '{}';", &element_ref),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 // Response is "true" or "false" string, or "1"/"0"
                 let is_displayed = response == "true" || response == "1";
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::Bool(is_displayed))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::Bool(is_displayed))))
             },
             ElementSendKeys(element_ref, keys) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    native_ui::element_request(&session, "nativeElementSendKeys", &element_ref.to_string(), &[("text", keys.text.as_str())])?;
+                    return Ok(WebDriverResponse::Void);
+                }
                 info!("ElementSendKeys called: element={}, keys={:?}", element_ref, keys);
                 let script_body = r#"
                 if (!window.__webdriver_script_results) {
@@ -1925,15 +2597,18 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let textToSend = {};", serde_json::to_string(text).unwrap()),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 info!("ElementSendKeys response: {:?}", response);
-                return Ok(WebDriverResponse::Void);
+                Ok(WebDriverResponse::Void)
             },
             ElementClear(element_ref) => {
+                let session = self.session(&msg.session_id)?;
+                if session.context() == DuckDuckGoContext::Chrome {
+                    native_ui::element_request(&session, "nativeElementClear", &element_ref.to_string(), &[])?;
+                    return Ok(WebDriverResponse::Void);
+                }
                 info!("ElementClear called: element={}", element_ref);
                 let script_body = r#"
                 if (!window.__webdriver_script_results) {
@@ -1966,93 +2641,93 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let elementId = '{}';", &element_ref),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 info!("ElementClear response: {:?}", response);
-                return Ok(WebDriverResponse::Void);
+                Ok(WebDriverResponse::Void)
             },
             GetTitle => {
+                let session = self.session(&msg.session_id)?;
+                session.require_content_context()?;
                 let script = "return document.title || '';";
-                let script = urlencoding::encode(script).to_string();
                 let mut params = std::collections::HashMap::new();
-                params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "execute", &params);
+                params.insert("script", script);
+                let response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 let title = serde_json::from_str::<Value>(&response)
                     .ok()
                     .and_then(|v| v.as_str().map(|s| s.to_string()))
                     .unwrap_or_default();
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(title))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(title))))
             },
             NewWindow(_) => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let window_handle = server_request_for_platform(session_id, &platform, "newWindow", &std::collections::HashMap::new());
+                let session = self.session(&msg.session_id)?;
+                let window_handle = session.request("newWindow", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 info!("New window handle: {:#?}", window_handle);
                 #[derive(Deserialize, Debug)]
                 struct ResponseNewWindow {
                     handle: String,
                     r#type: String
                 }
-                let response: ResponseNewWindow = serde_json::from_str(&window_handle).expect("Failed to parse window handles");
+                let response: ResponseNewWindow = parse_message("newWindow", &window_handle)?;
                 info!("Window handle json: {:#?}", response);
-                return Ok(WebDriverResponse::NewWindow(NewWindowResponse {
+                Ok(WebDriverResponse::NewWindow(NewWindowResponse {
                     handle: response.handle,
                     typ: response.r#type,
-                }));
+                }))
             },
             CloseWindow => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let window_handle = server_request_for_platform(session_id, &platform, "closeWindow", &std::collections::HashMap::new());
+                let session = self.session(&msg.session_id)?;
+                let window_handle = session.request("closeWindow", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 info!("Close window handle: {:#?}", window_handle);
 
-                let window_handles = server_request_for_platform(session_id, &platform, "getWindowHandles", &std::collections::HashMap::new());
+                let window_handles = session.request("getWindowHandles", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 // Parse json string
-                let window_handles: Vec<String> = serde_json::from_str(&window_handles).expect("Failed to parse window handles");
+                let window_handles: Vec<String> = parse_message("getWindowHandles", &window_handles)?;
                 info!("Window handles: {:#?}", window_handles);
-                return Ok(WebDriverResponse::Generic(ValueResponse(window_handles.into())));
+                Ok(WebDriverResponse::Generic(ValueResponse(window_handles.into())))
             },
             SwitchToWindow(params_in) => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
+                let session = self.session(&msg.session_id)?;
                 let mut params = std::collections::HashMap::new();
                 params.insert("handle", params_in.handle.as_str());
-                server_request_for_platform(session_id, &platform, "switchToWindow", &params);
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)));
+                session.request("switchToWindow", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)))
             },
             GetWindowHandle => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let window_handle = server_request_for_platform(session_id, &platform, "getWindowHandle", &std::collections::HashMap::new());
+                let session = self.session(&msg.session_id)?;
+                let window_handle = session.request("getWindowHandle", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 info!("Window handle: {:#?}", window_handle);
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(window_handle))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(window_handle))))
             },
             GetWindowHandles => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let window_handles = server_request_for_platform(session_id, &platform, "getWindowHandles", &std::collections::HashMap::new());
+                let session = self.session(&msg.session_id)?;
+                let window_handles = session.request("getWindowHandles", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 // Parse json string
-                let window_handles: Vec<String> = serde_json::from_str(&window_handles).expect("Failed to parse window handles");
+                let window_handles: Vec<String> = parse_message("getWindowHandles", &window_handles)?;
                 info!("Window handles: {:#?}", window_handles);
-                return Ok(WebDriverResponse::Generic(ValueResponse(window_handles.into())));
+                Ok(WebDriverResponse::Generic(ValueResponse(window_handles.into())))
             },
             GetCurrentUrl => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                info!("Session {:?}", session_id);
-                let url_string = server_request_for_platform(session_id, &platform, "getUrl", &std::collections::HashMap::new());
+                let session = self.session(&msg.session_id)?;
+                let url_string = session.request("getUrl", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 info!("UrlString response: {:#?}", url_string);
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(url_string))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(url_string))))
             },
             ReleaseActions | PerformActions(_) => {
                 info!("Actions command - no-op, returning void");
-                return Ok(WebDriverResponse::Void);
+                Ok(WebDriverResponse::Void)
             },
             TakeScreenshot => {
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let response = server_request_for_platform(session_id, &platform, "screenshot", &std::collections::HashMap::new());
+                let session = self.session(&msg.session_id)?;
+                let response = session.request("screenshot", &std::collections::HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
                 // WebDriver spec requires base64-encoded PNG data
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))));
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))))
             },
             TakeElementScreenshot(element_ref) => {
+                // The element is looked up among the page's elements
+                let session = self.session(&msg.session_id)?;
+                session.require_content_context()?;
                 let script_body = r#"
                 if (!window.__webdriver_script_results) {
                     return null;
@@ -2074,40 +2749,250 @@ fn set_ios_config_url_fallback(udid: &str, config_url: &str) {
                     format!("let elementId = '{}';", &element_ref),
                     script_body.to_string(),
                 ].join(" ");
-                let script = urlencoding::encode(&script).to_string();
                 let mut params = std::collections::HashMap::new();
                 params.insert("script", script.as_str());
-                let session_id = msg.session_id.as_ref().expect("Expected a session id");
-                let rect_response = server_request_for_platform(session_id, &platform, "execute", &params);
+                let rect_response = session.request("execute", &params, Some(DEFAULT_REQUEST_TIMEOUT))?;
                 
                 // Parse the rect JSON and pass to screenshot endpoint
                 let mut screenshot_params = std::collections::HashMap::new();
                 screenshot_params.insert("rect", rect_response.as_str());
-                let response = server_request_for_platform(session_id, &platform, "screenshot", &screenshot_params);
-                return Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))));
+                let response = session.request("screenshot", &screenshot_params, Some(DEFAULT_REQUEST_TIMEOUT))?;
+                Ok(WebDriverResponse::Generic(ValueResponse(Value::String(response))))
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::SetPrivacyConfig(ref requested)) => {
+                let session = self.session(&msg.session_id)?;
+                let ready = change_privacy_config(&session, requested, &self.config_cache)?;
+                Ok(WebDriverResponse::Generic(ValueResponse(serde_json::json!({
+                    "contentBlockerReadyMs": ready.as_millis() as u64,
+                }))))
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::GetContext) => {
+                let session = self.session(&msg.session_id)?;
+                Ok(WebDriverResponse::Generic(ValueResponse(serde_json::to_value(session.context()).unwrap())))
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::SetContext(ref parameters)) => {
+                let session = self.session(&msg.session_id)?;
+                info!("Session {:?} switching to the {:?} context", msg.session_id, parameters.context);
+                *session.context.lock().unwrap() = parameters.context;
+                Ok(WebDriverResponse::Void)
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::SetProtection(ref parameters)) => {
+                let session = self.session(&msg.session_id)?;
+                Ok(WebDriverResponse::Generic(ValueResponse(toggle_protection(&session, parameters)?)))
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::ClearData(ref parameters)) => {
+                let session = self.session(&msg.session_id)?;
+                clear_browsing_data(session.port, parameters)?;
+                Ok(WebDriverResponse::Void)
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::SetFeatureFlags(ref flags)) => {
+                let session = self.session(&msg.session_id)?;
+                let resolved = feature_flags::set_overrides(&session, flags)?;
+                // Later ddg:reuseApp sessions attach only if they ask for the same overrides
+                let mut ddg_caps = session.ddg_caps.lock().unwrap();
+                ddg_caps.feature_flags.get_or_insert_with(FeatureFlags::new).extend(flags.clone());
+                Ok(WebDriverResponse::Generic(ValueResponse(
+                    serde_json::to_value(resolved).expect("Failed to serialize feature flags"),
+                )))
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::GetTrackerReport) => {
+                let session = self.session(&msg.session_id)?;
+                let report = tracker_report::current_tab(&session)?;
+                Ok(WebDriverResponse::Generic(ValueResponse(
+                    serde_json::to_value(report).expect("Failed to serialize the tracker report"),
+                )))
+            },
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::GetPrivacyConfig) => {
+                let session = self.session(&msg.session_id)?;
+                let mut report = serde_json::to_value(&*session.privacy_config.lock().unwrap())
+                    .expect("Failed to serialize the installed privacy config");
+                // Older app builds can't report the config they loaded
+                report["appVersion"] = match session.request("getPrivacyConfigVersion", &HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT)) {
+                    Ok(version) => serde_json::from_str(&version).unwrap_or(Value::String(version)),
+                    Err(e) => {
+                        warn!("App on port {} did not report its privacy config version: {}", session.port, e);
+                        Value::Null
+                    }
+                };
+                Ok(WebDriverResponse::Generic(ValueResponse(report)))
             },
             _ => {
                 info!("Unhandled command: {:?}", msg.command);
                 Ok(WebDriverResponse::Generic(ValueResponse(Value::Null)))
             },
-        };
+        }
      }
- 
-    fn teardown_session(&mut self, kind: SessionTeardownKind) {
-       info!("Tearing down session (kind: {:?})", kind);
-       
-       let platform = Platform::from_env();
-       match platform {
-           Platform::MacOS => {
-               if matches!(kind, SessionTeardownKind::Deleted) {
-                   // Quit app via /shutdown endpoint which cleanly terminates
-                   // without crash dialogs (no port known here, use fallback)
-                   quit_macos_app_with_port(None);
-               }
-           },
-           Platform::IOS => {
-               // iOS cleanup handled by DeleteSession command
-           }
-       }
-    }
-}
\ No newline at end of file
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config_cache() -> ConfigCache {
+        ConfigCache::new(std::env::temp_dir().join("ddgdriver-handler-tests"), true)
+    }
+
+    #[test]
+    fn parses_context_switches() {
+        let command = DuckDuckGoExtensionRoute::SetContext
+            .command(&Parameters::new(), &serde_json::json!({ "context": "chrome" }))
+            .unwrap();
+        match command {
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::SetContext(parameters)) => {
+                assert_eq!(parameters.context, DuckDuckGoContext::Chrome)
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        let err = DuckDuckGoExtensionRoute::SetContext
+            .command(&Parameters::new(), &serde_json::json!({ "context": "page" }))
+            .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn parses_protection_toggles() {
+        let command = DuckDuckGoExtensionRoute::SetProtection
+            .command(&Parameters::new(), &serde_json::json!({ "domain": "example.com", "protected": false }))
+            .unwrap();
+        match command {
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::SetProtection(parameters)) => {
+                assert_eq!(parameters.domain.as_deref(), Some("example.com"));
+                assert!(!parameters.protected);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        for body in [serde_json::json!({ "domain": "example.com" }), serde_json::json!({ "domain": "", "protected": true })] {
+            let err = DuckDuckGoExtensionRoute::SetProtection.command(&Parameters::new(), &body).unwrap_err();
+            assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn parses_clear_data_options() {
+        let command = DuckDuckGoExtensionRoute::ClearData.command(&Parameters::new(), &serde_json::json!({})).unwrap();
+        match command {
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::ClearData(parameters)) => {
+                assert_eq!(parameters, DuckDuckGoClearDataParameters::default());
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        let command = DuckDuckGoExtensionRoute::ClearData
+            .command(&Parameters::new(), &serde_json::json!({ "scope": "currentSite", "keepFireproofed": true }))
+            .unwrap();
+        match command {
+            WebDriverCommand::Extension(DuckDuckGoExtensionCommand::ClearData(parameters)) => {
+                assert_eq!(parameters.scope, ClearDataScope::CurrentSite);
+                assert!(parameters.keep_fireproofed);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        let err = DuckDuckGoExtensionRoute::ClearData
+            .command(&Parameters::new(), &serde_json::json!({ "scope": "yesterday" }))
+            .unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn numbers_simulators_after_the_first() {
+        assert_eq!(simulator_name("iPhone-16", "iOS-18-2", 1), "iPhone-16 iOS-18-2 (webdriver)");
+        assert_eq!(simulator_name("iPhone-16", "iOS-18-2", 3), "iPhone-16 iOS-18-2 (webdriver 3)");
+        assert_eq!(simulator_index("iPhone-16 iOS-18-2 (webdriver)", "iPhone-16", "iOS-18-2"), Some(1));
+        assert_eq!(simulator_index("iPhone-16 iOS-18-2 (webdriver 3)", "iPhone-16", "iOS-18-2"), Some(3));
+        assert_eq!(simulator_index("iPhone-16 iOS-18-2 (webdriver x)", "iPhone-16", "iOS-18-2"), None);
+        assert_eq!(simulator_index("iPhone-16 iOS-18-2 (webdriver 3)", "iPhone-15", "iOS-18-2"), None);
+    }
+
+    /// Automation server answering `/contentBlockerReady` with each of
+    /// `states` in turn, then with the last one. Also returns how many
+    /// requests it answered.
+    fn content_blocker_server(states: &'static [bool]) -> (u16, Arc<AtomicUsize>) {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let answered = Arc::new(AtomicUsize::new(0));
+        let counter = answered.clone();
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else { return };
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    line.clear();
+                }
+                let ready = states[index.min(states.len() - 1)];
+                let body = format!(r#"{{"message":"{}","requestPath":"/contentBlockerReady"}}"#, ready);
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = reader.get_mut().write_all(response.as_bytes());
+            }
+        });
+        (port, answered)
+    }
+
+    #[test]
+    fn rules_reload_waits_past_the_previous_ready() {
+        // The previous rules still report ready before the app starts recompiling
+        let (port, answered) = content_blocker_server(&[true, true, false, false, true]);
+        wait_for_rules_reload(port, "the test").unwrap();
+        assert_eq!(answered.load(std::sync::atomic::Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn queues_sessions_over_the_limit() {
+        let handler = Arc::new(Handler::new(1, None, None, test_config_cache()));
+        let slot = handler.reserve_session(Platform::IOS).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiting = {
+            let handler = handler.clone();
+            thread::spawn(move || {
+                let _slot = handler.reserve_session(Platform::IOS).unwrap();
+                sender.send(()).unwrap();
+            })
+        };
+        assert!(receiver.recv_timeout(std::time::Duration::from_millis(200)).is_err());
+        drop(slot);
+        receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+        assert_eq!(handler.reservations.lock().unwrap().sessions, 0);
+    }
+
+    #[test]
+    fn allocates_ports_that_are_neither_reserved_nor_bound() {
+        let handler = Handler::new(2, None, None, test_config_cache());
+        handler.reservations.lock().unwrap().ports.insert(8557);
+        // Held for the test, so the probe finds it taken
+        let busy = std::net::TcpListener::bind(("0.0.0.0", 8558)).ok();
+        let mut slot = handler.reserve_session(Platform::IOS).unwrap();
+        let port = slot.allocate_port().unwrap();
+        assert!(port > 8557);
+        if busy.is_some() {
+            assert!(port > 8558);
+            assert!(!handler.reservations.lock().unwrap().ports.contains(&8558));
+        }
+        assert!(handler.reservations.lock().unwrap().ports.contains(&port));
+        drop(slot);
+        assert!(!handler.reservations.lock().unwrap().ports.contains(&port));
+    }
+
+    #[test]
+    fn shutdown_turns_away_queued_sessions() {
+        let handler = Arc::new(Handler::new(1, None, None, test_config_cache()));
+        let slot = handler.reserve_session(Platform::IOS).unwrap();
+        let waiting = {
+            let handler = handler.clone();
+            thread::spawn(move || handler.reserve_session(Platform::IOS).map(|_| ()))
+        };
+        thread::sleep(Duration::from_millis(100));
+        let shutting_down = {
+            let handler = handler.clone();
+            thread::spawn(move || handler.shutdown())
+        };
+        let err = waiting.join().unwrap().unwrap_err();
+        assert_eq!(err.error, ErrorStatus::SessionNotCreated);
+        // Shutdown waits for the session that is still starting
+        drop(slot);
+        shutting_down.join().unwrap();
+        assert!(handler.reserve_session(Platform::MacOS).is_err());
+    }
+}
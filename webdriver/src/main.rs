@@ -7,16 +7,36 @@ extern crate serde;
 extern crate serde_derive;
 extern crate serde_json;
 
+mod app;
+mod automation;
+mod capabilities;
+mod config_cache;
+mod feature_flags;
 mod handler;
+mod native_ui;
+mod pool;
+mod remote_config;
+mod reuse;
+mod server;
+mod session;
+mod tracker_report;
 
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::{bail, Result as ProgramResult};
 use url::{Host, Url};
-use crate::handler::{Handler};
+use crate::app::AppBundle;
+use crate::capabilities::DdgCapabilities;
+use crate::config_cache::ConfigCache;
+use crate::handler::{DuckDuckGoExtensionRoute, Handler, Platform};
+use crate::pool::SimulatorPool;
 use std::process::ExitCode;
 use std::env;
 const EXIT_UNAVAILABLE: u8 = 69;
 use clap::Parser;
+use tokio::signal::unix::{signal, SignalKind};
+use warp::http::Method;
 
 
 /// Simple program to greet a person
@@ -26,6 +46,22 @@ struct Args {
     /// Name of the person to greet
     #[arg(short, long)]
     port: u16,
+
+    /// Most sessions to run at once; further NewSession requests wait for one to end
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    max_sessions: u16,
+
+    /// iOS simulators to keep booted with the app installed; 0 disables the pool
+    #[arg(long, default_value_t = 0)]
+    pool_size: u16,
+
+    /// Seconds a session may go without commands before it is ended; 0 disables the timeout
+    #[arg(long, default_value_t = 600)]
+    idle_timeout: u64,
+
+    /// Only use remote configs already in the config cache; never fetch them
+    #[arg(long)]
+    offline: bool,
 }
 
 /// Get a socket address from the provided host and port
@@ -76,7 +112,11 @@ fn main() -> ExitCode {
     let args = Args::parse();
     let port = args.port;
 
-    if let Err(e) = inner_main(port) {
+    let idle_timeout = (args.idle_timeout > 0).then(|| Duration::from_secs(args.idle_timeout));
+
+    let config_cache = ConfigCache::from_env(args.offline);
+
+    if let Err(e) = inner_main(port, usize::from(args.max_sessions), usize::from(args.pool_size), idle_timeout, config_cache) {
         info!("{}: error: {}", get_program_name(), e);
         //print_help(&mut cmd);
         return ExitCode::from(EXIT_UNAVAILABLE);
@@ -85,21 +125,78 @@ fn main() -> ExitCode {
     ExitCode::SUCCESS
 }
 
-fn inner_main(port: u16) -> ProgramResult<()> {
+/// Start preparing the simulator pool for the default device, runtime and app
+fn simulator_pool(pool_size: usize, driver_port: u16) -> Option<Arc<SimulatorPool>> {
+    if pool_size == 0 {
+        return None;
+    }
+    let defaults = DdgCapabilities::default();
+    match AppBundle::resolve(Platform::IOS, &defaults) {
+        Ok(app) => Some(SimulatorPool::start(
+            pool_size,
+            defaults.device_type(),
+            defaults.os_runtime(),
+            app.path.to_string_lossy().into_owned(),
+            driver_port,
+        )),
+        Err(e) => {
+            info!("Simulator pool disabled: {}", e.message);
+            None
+        }
+    }
+}
+
+/// Block until the process is asked to stop with SIGINT or SIGTERM
+fn wait_for_signal() -> ProgramResult<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    rt.block_on(async {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    })?;
+    Ok(())
+}
+
+fn inner_main(
+    port: u16,
+    max_sessions: usize,
+    pool_size: usize,
+    idle_timeout: Option<Duration>,
+    config_cache: ConfigCache,
+) -> ProgramResult<()> {
     let address = server_address("localhost", port).unwrap();
     let allow_hosts = vec![Host::Domain("localhost".to_string())];
     let origin = format!("http://localhost:{}", port);
     let allow_origins = vec![Url::parse(&origin).unwrap()];
-    let handler = Handler::new();
+    let handler = Arc::new(Handler::new(max_sessions, simulator_pool(pool_size, port), idle_timeout, config_cache));
+    handler.spawn_idle_reaper();
     info!("Starting server on {}", address);
-    let listening = webdriver::server::start(
+    let listening = server::start(
         address,
         allow_hosts,
         allow_origins,
-        handler,
-        vec![],
+        handler.clone(),
+        vec![
+            (Method::GET, "/session/{sessionId}/ddg/context", DuckDuckGoExtensionRoute::GetContext),
+            (Method::POST, "/session/{sessionId}/ddg/context", DuckDuckGoExtensionRoute::SetContext),
+            (Method::POST, "/session/{sessionId}/ddg/privacy-config", DuckDuckGoExtensionRoute::SetPrivacyConfig),
+            (Method::GET, "/session/{sessionId}/ddg/privacy-config", DuckDuckGoExtensionRoute::GetPrivacyConfig),
+            (Method::POST, "/session/{sessionId}/ddg/protection", DuckDuckGoExtensionRoute::SetProtection),
+            (Method::GET, "/session/{sessionId}/ddg/trackers", DuckDuckGoExtensionRoute::GetTrackerReport),
+            (Method::POST, "/session/{sessionId}/ddg/clear-data", DuckDuckGoExtensionRoute::ClearData),
+            (Method::POST, "/session/{sessionId}/ddg/feature-flags", DuckDuckGoExtensionRoute::SetFeatureFlags),
+        ],
     )?;
 
     info!("Listening on {}", listening.socket);
-    return Ok(());
+    wait_for_signal()?;
+    info!("Shutting down, ending all sessions");
+    handler.shutdown();
+    listening.stop();
+    info!("Stopped");
+    Ok(())
 }
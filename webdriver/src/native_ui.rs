@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+use webdriver::command::LocatorParameters;
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+use crate::automation::{parse_message, DEFAULT_REQUEST_TIMEOUT};
+use crate::session::DriverSession;
+
+/// Elements of the browser's native UI (address bar, tab switcher, privacy
+/// dashboard, …) matching a locator, found by the automation server instead
+/// of a script in the page. The app polls for up to the session's implicit
+/// wait, like the content lookup script does.
+pub fn find_elements(session: &DriverSession, locator: &LocatorParameters) -> WebDriverResult<Vec<String>> {
+    let timeouts = session.timeouts();
+    let using = serde_json::to_value(locator.using).expect("Failed to serialize locator strategy");
+    let implicit_wait = timeouts.implicit.to_string();
+    let params = HashMap::from([
+        ("using", using.as_str().unwrap_or_default()),
+        ("value", locator.value.as_str()),
+        ("implicitWait", implicit_wait.as_str()),
+    ]);
+    let response = session.request("findNativeElements", &params, timeouts.element_lookup_deadline())?;
+    Ok(parse_message("findNativeElements", &response)?)
+}
+
+/// First native UI element matching a locator
+pub fn find_element(session: &DriverSession, locator: &LocatorParameters) -> WebDriverResult<String> {
+    find_elements(session, locator)?.into_iter().next().ok_or_else(|| {
+        WebDriverError::new(
+            ErrorStatus::NoSuchElement,
+            format!("No native element matches {:?} {}", locator.using, locator.value),
+        )
+    })
+}
+
+/// Run an element command against a native UI element, returning the
+/// automation server's message
+pub fn element_request(session: &DriverSession, method: &str, element_id: &str, extra: &[(&str, &str)]) -> WebDriverResult<String> {
+    let mut params = HashMap::from([("id", element_id)]);
+    params.extend(extra.iter().copied());
+    session.request(method, &params, Some(DEFAULT_REQUEST_TIMEOUT))
+}
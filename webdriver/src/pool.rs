@@ -0,0 +1,380 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde_json::Value;
+
+use crate::app::content_hash;
+use crate::handler::{install_ca_cert, xcrun_command};
+
+/// Where a pool device is in its lease cycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DeviceState {
+    /// Being cloned from the golden device and booted
+    Preparing,
+    /// Booted with a fresh copy of the golden device, waiting for a session
+    Ready,
+    /// In use by a session
+    Leased,
+    /// Cloning or booting failed
+    Failed,
+}
+
+struct PoolDevice {
+    name: String,
+    udid: Option<String>,
+    state: DeviceState,
+}
+
+struct PoolState {
+    golden_udid: Option<String>,
+    /// `content_hash` of the app build installed on the golden device
+    app_hash: Option<String>,
+    /// Why the golden device could not be prepared, if it could not
+    error: Option<String>,
+    devices: Vec<PoolDevice>,
+    /// The driver is exiting; no more devices are cloned or booted
+    shutting_down: bool,
+}
+
+/// Booted simulators cloned from a golden device that already has the app
+/// and the CA certificate installed, so iOS sessions skip booting and
+/// reinstalling. Devices come back from sessions as fresh clones.
+pub struct SimulatorPool {
+    device_type: String,
+    os_runtime: String,
+    app_path: String,
+    /// Port of the driver that owns the pool, part of its device names so
+    /// drivers on other ports leave them alone
+    driver_port: u16,
+    state: Mutex<PoolState>,
+}
+
+impl SimulatorPool {
+    /// Prepare the golden device and `size` clones in the background
+    pub fn start(size: usize, device_type: String, os_runtime: String, app_path: String, driver_port: u16) -> Arc<Self> {
+        let pool = Arc::new(SimulatorPool::new(size, device_type, os_runtime, app_path, driver_port));
+        let preparing = pool.clone();
+        thread::spawn(move || {
+            preparing.remove_stale_clones();
+            preparing.prepare();
+        });
+        pool
+    }
+
+    fn new(size: usize, device_type: String, os_runtime: String, app_path: String, driver_port: u16) -> Self {
+        let devices = (1..=size)
+            .map(|index| PoolDevice {
+                name: format!("{device_type} {os_runtime} (webdriver pool {driver_port} {index})"),
+                udid: None,
+                state: DeviceState::Preparing,
+            })
+            .collect();
+        SimulatorPool {
+            device_type,
+            os_runtime,
+            app_path,
+            driver_port,
+            state: Mutex::new(PoolState {
+                golden_udid: None,
+                app_hash: None,
+                error: None,
+                devices,
+                shutting_down: false,
+            }),
+        }
+    }
+
+    /// Clones an earlier run of this driver left behind, possibly made from an older app build
+    fn remove_stale_clones(&self) {
+        let prefix = format!("{} {} (webdriver pool {} ", self.device_type, self.os_runtime, self.driver_port);
+        for (udid, device_name) in list_devices(&self.os_runtime) {
+            if device_name.starts_with(&prefix) {
+                delete_device(&udid);
+            }
+        }
+    }
+
+    /// Install the app on the golden device and replace every device that
+    /// isn't leased with a fresh clone of it. Leased devices are replaced
+    /// when they are given back.
+    fn prepare(self: Arc<Self>) {
+        info!("Preparing simulator pool for {} {}", self.device_type, self.os_runtime);
+        let (golden, app_hash) = match self.prepare_golden() {
+            Ok(golden) => golden,
+            Err(e) => {
+                info!("Simulator pool disabled: {}", e);
+                let mut state = self.state.lock().unwrap();
+                state.error = Some(e);
+                for device in state.devices.iter_mut() {
+                    device.state = DeviceState::Failed;
+                }
+                return;
+            }
+        };
+        let names: Vec<String> = {
+            let mut state = self.state.lock().unwrap();
+            state.golden_udid = Some(golden);
+            state.app_hash = Some(app_hash);
+            state.error = None;
+            state.devices
+                .iter()
+                .filter(|device| device.state != DeviceState::Leased)
+                .map(|device| device.name.clone())
+                .collect()
+        };
+        for name in names {
+            self.refresh(&name);
+        }
+    }
+
+    /// Create the golden device if needed and install the current app build
+    /// and the CA certificate on it. Cloning needs it shut down afterwards.
+    /// Returns the device and the `content_hash` of the installed build.
+    fn prepare_golden(&self) -> Result<(String, String), String> {
+        let name = format!("{} {} (webdriver golden {})", self.device_type, self.os_runtime, self.driver_port);
+        let app_hash = content_hash(Path::new(&self.app_path))
+            .map_err(|e| format!("Failed to read the app bundle {}: {}", self.app_path, e))?;
+        let devices = list_devices(&self.os_runtime);
+        let udid = match devices.iter().find(|(_, device_name)| *device_name == name) {
+            Some((udid, _)) => udid.clone(),
+            None => {
+                let output = xcrun_command(&[
+                    "simctl",
+                    "create",
+                    &name,
+                    &format!("com.apple.CoreSimulator.SimDeviceType.{}", self.device_type),
+                    &format!("com.apple.CoreSimulator.SimRuntime.{}", self.os_runtime),
+                ]);
+                if !output.status.success() {
+                    return Err(format!("Failed to create golden simulator {}", name));
+                }
+                String::from_utf8_lossy(&output.stdout).trim().to_string()
+            }
+        };
+        info!("Golden simulator {} ({})", name, udid);
+        xcrun_command(&["simctl", "boot", &udid]);
+        xcrun_command(&["simctl", "bootstatus", &udid]);
+        install_ca_cert(&udid);
+        let install = xcrun_command(&["simctl", "install", &udid, &self.app_path]);
+        xcrun_command(&["simctl", "shutdown", &udid]);
+        if !install.status.success() {
+            return Err(format!("Failed to install {} on the golden simulator", self.app_path));
+        }
+        Ok((udid, app_hash))
+    }
+
+    /// Replace a pool device with a fresh clone of the golden device and boot it
+    fn refresh(&self, name: &str) {
+        let (golden, old_udid) = {
+            let mut state = self.state.lock().unwrap();
+            let golden = state.golden_udid.clone().filter(|_| !state.shutting_down);
+            let device = state.devices.iter_mut().find(|device| device.name == name).unwrap();
+            device.state = DeviceState::Preparing;
+            (golden, device.udid.take())
+        };
+        if let Some(udid) = old_udid {
+            delete_device(&udid);
+        }
+        let Some(golden) = golden else {
+            return;
+        };
+        let clone = xcrun_command(&["simctl", "clone", &golden, name]);
+        let udid = String::from_utf8_lossy(&clone.stdout).trim().to_string();
+        if self.state.lock().unwrap().shutting_down {
+            if clone.status.success() {
+                delete_device(&udid);
+            }
+            return;
+        }
+        let booted = clone.status.success()
+            && xcrun_command(&["simctl", "boot", &udid]).status.success()
+            && xcrun_command(&["simctl", "bootstatus", &udid]).status.success();
+        let mut state = self.state.lock().unwrap();
+        let device = state.devices.iter_mut().find(|device| device.name == name).unwrap();
+        if booted {
+            info!("Pool simulator {} ({}) ready", name, udid);
+            device.state = DeviceState::Ready;
+        } else {
+            info!("Failed to prepare pool simulator {}", name);
+            device.state = DeviceState::Failed;
+        }
+        if !udid.is_empty() {
+            device.udid = Some(udid);
+        }
+    }
+
+    /// Lease a ready device if the pool was built for this device type,
+    /// runtime and app. If the app at the pool's path was rebuilt since, the
+    /// pool is rebuilt in the background and nothing is leased.
+    pub fn lease(self: &Arc<Self>, device_type: &str, os_runtime: &str, app_path: &str) -> Option<String> {
+        if device_type != self.device_type || os_runtime != self.os_runtime || app_path != self.app_path {
+            return None;
+        }
+        let app_hash = content_hash(Path::new(app_path)).ok()?;
+        self.lease_build(&app_hash)
+    }
+
+    fn lease_build(self: &Arc<Self>, app_hash: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        if state.app_hash.as_deref() != Some(app_hash) {
+            // Still being prepared if no build is installed yet
+            if state.app_hash.is_some() && !state.shutting_down {
+                info!("App at {} changed, rebuilding the simulator pool", self.app_path);
+                state.golden_udid = None;
+                state.app_hash = None;
+                for device in state.devices.iter_mut().filter(|device| device.state == DeviceState::Ready) {
+                    device.state = DeviceState::Preparing;
+                }
+                let pool = self.clone();
+                thread::spawn(move || pool.prepare());
+            }
+            return None;
+        }
+        let device = state.devices.iter_mut().find(|device| device.state == DeviceState::Ready)?;
+        device.state = DeviceState::Leased;
+        info!("Leased pool simulator {}", device.name);
+        device.udid.clone()
+    }
+
+    /// Take a device back from a session and reset it in the background
+    pub fn give_back(self: &Arc<Self>, udid: &str) {
+        let name = {
+            let state = self.state.lock().unwrap();
+            if state.shutting_down {
+                xcrun_command(&["simctl", "shutdown", udid]);
+                return;
+            }
+            match state.devices.iter().find(|device| device.udid.as_deref() == Some(udid)) {
+                Some(device) => device.name.clone(),
+                None => return,
+            }
+        };
+        info!("Resetting pool simulator {}", name);
+        let pool = self.clone();
+        thread::spawn(move || pool.refresh(&name));
+    }
+
+    /// Whether `udid` belongs to the pool
+    pub fn contains(&self, udid: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.devices.iter().any(|device| device.udid.as_deref() == Some(udid))
+    }
+
+    /// Shut down every pool simulator when the driver exits
+    pub fn shutdown(&self) {
+        let udids: Vec<String> = {
+            let mut state = self.state.lock().unwrap();
+            state.shutting_down = true;
+            state.devices.iter().filter_map(|device| device.udid.clone()).collect()
+        };
+        for udid in udids {
+            info!("Shutting down pool simulator {}", udid);
+            xcrun_command(&["simctl", "shutdown", &udid]);
+        }
+    }
+
+    /// Pool state as reported by `Status`
+    pub fn status(&self) -> Value {
+        let state = self.state.lock().unwrap();
+        let count = |wanted: DeviceState| state.devices.iter().filter(|device| device.state == wanted).count();
+        serde_json::json!({
+            "deviceType": self.device_type,
+            "osRuntime": self.os_runtime,
+            "app": self.app_path,
+            "size": state.devices.len(),
+            "ready": count(DeviceState::Ready),
+            "leased": count(DeviceState::Leased),
+            "preparing": count(DeviceState::Preparing),
+            "failed": count(DeviceState::Failed),
+            "appHash": state.app_hash,
+            "error": state.error,
+        })
+    }
+}
+
+/// Devices of the given runtime as (udid, name) pairs
+fn list_devices(os_runtime: &str) -> Vec<(String, String)> {
+    let output = xcrun_command(&["simctl", "list", "devices", "-j"]);
+    let Ok(simulators) = serde_json::from_slice::<Value>(&output.stdout) else {
+        return Vec::new();
+    };
+    let mut devices = Vec::new();
+    if let Some(runtimes) = simulators.get("devices").and_then(|devices| devices.as_object()) {
+        for (runtime, device_list) in runtimes {
+            if !runtime.contains(os_runtime) {
+                continue;
+            }
+            for device in device_list.as_array().into_iter().flatten() {
+                if let (Some(udid), Some(name)) = (device["udid"].as_str(), device["name"].as_str()) {
+                    devices.push((udid.to_string(), name.to_string()));
+                }
+            }
+        }
+    }
+    devices
+}
+
+fn delete_device(udid: &str) {
+    xcrun_command(&["simctl", "shutdown", udid]);
+    xcrun_command(&["simctl", "delete", udid]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(size: usize) -> Arc<SimulatorPool> {
+        let pool = SimulatorPool::new(size, "iPhone-16".to_string(), "iOS-18-2".to_string(), "/apps/DuckDuckGo.app".to_string(), 4444);
+        pool.state.lock().unwrap().app_hash = Some("build-1".to_string());
+        Arc::new(pool)
+    }
+
+    #[test]
+    fn leases_ready_devices_for_the_pool_target_only() {
+        let pool = pool(2);
+        {
+            let mut state = pool.state.lock().unwrap();
+            state.devices[1].udid = Some("UDID-2".to_string());
+            state.devices[1].state = DeviceState::Ready;
+        }
+        assert_eq!(pool.lease("iPhone-15", "iOS-18-2", "/apps/DuckDuckGo.app"), None);
+        assert_eq!(pool.lease("iPhone-16", "iOS-18-2", "/other/DuckDuckGo.app"), None);
+        assert_eq!(pool.lease_build("build-1").as_deref(), Some("UDID-2"));
+        assert_eq!(pool.lease_build("build-1"), None);
+        assert!(pool.contains("UDID-2"));
+        assert_eq!(pool.state.lock().unwrap().devices[0].name, "iPhone-16 iOS-18-2 (webdriver pool 4444 1)");
+
+        let status = pool.status();
+        assert_eq!(status["leased"], 1);
+        assert_eq!(status["preparing"], 1);
+        assert_eq!(status["ready"], 0);
+    }
+
+    #[test]
+    fn rebuilt_apps_are_not_leased_from_the_old_build() {
+        let pool = pool(1);
+        {
+            let mut state = pool.state.lock().unwrap();
+            state.devices[0].udid = Some("UDID-1".to_string());
+            state.devices[0].state = DeviceState::Ready;
+        }
+        assert_eq!(pool.lease_build("build-2"), None);
+        // The ready clone was made from the old build and waits for the rebuild
+        assert_eq!(pool.status()["ready"], 0);
+        assert_eq!(pool.lease_build("build-1"), None);
+    }
+
+    #[test]
+    fn no_clones_are_made_after_shutdown() {
+        let pool = pool(1);
+        {
+            let mut state = pool.state.lock().unwrap();
+            state.golden_udid = Some("GOLDEN".to_string());
+            state.shutting_down = true;
+        }
+        let name = pool.state.lock().unwrap().devices[0].name.clone();
+        pool.refresh(&name);
+        assert_eq!(pool.state.lock().unwrap().devices[0].udid, None);
+    }
+}
@@ -0,0 +1,529 @@
+use serde_json::Value;
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+use crate::capabilities::DdgCapabilities;
+use crate::config_cache::{content_etag, content_hash, ConfigCache};
+
+/// What to do with a remote config that can't be fetched or doesn't look like one
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValidationMode {
+    /// Fail NewSession
+    #[default]
+    Strict,
+    /// Log a warning and install it anyway
+    Lenient,
+}
+
+/// Remote configuration files the apps download and cache, and which a
+/// session can pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigKind {
+    PrivacyConfiguration,
+    TrackerDataSet,
+    Surrogates,
+}
+
+impl ConfigKind {
+    /// Name the apps use for the config in `CustomConfigurationURL.*`, etag
+    /// keys and the iOS cache file
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigKind::PrivacyConfiguration => "privacyConfiguration",
+            ConfigKind::TrackerDataSet => "trackerDataSet",
+            ConfigKind::Surrogates => "surrogates",
+        }
+    }
+
+    /// Name for logs and error messages
+    pub fn label(&self) -> &'static str {
+        match self {
+            ConfigKind::PrivacyConfiguration => "privacy config",
+            ConfigKind::TrackerDataSet => "tracker data set",
+            ConfigKind::Surrogates => "surrogates",
+        }
+    }
+
+    /// File the macOS app caches the config in
+    pub fn macos_file_name(&self) -> &'static str {
+        match self {
+            ConfigKind::PrivacyConfiguration => "macos-config.json",
+            ConfigKind::TrackerDataSet => "tds.json",
+            ConfigKind::Surrogates => "surrogates.txt",
+        }
+    }
+
+    /// Defaults key the macOS app keeps the etag of its cached copy under
+    pub fn macos_etag_key(&self) -> &'static str {
+        match self {
+            ConfigKind::PrivacyConfiguration => "config.storage.privacyconfiguration.etag",
+            ConfigKind::TrackerDataSet => "config.storage.trackerradar.etag",
+            ConfigKind::Surrogates => "config.storage.surrogates.etag",
+        }
+    }
+}
+
+/// Remote configuration a session asked the driver to install, fetched,
+/// patched and checked before anything is launched
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteConfig {
+    kind: ConfigKind,
+    /// Config bytes to write into the app's config cache, or why they could not be fetched
+    data: Result<Vec<u8>, String>,
+    /// URL the app can download the config from itself if writing the cache fails
+    fallback_url: Option<String>,
+}
+
+impl RemoteConfig {
+    /// Resolve the privacy config, tracker data set and surrogates a session
+    /// asked for into the configs to install
+    pub fn from_capabilities(ddg_caps: &DdgCapabilities, cache: &ConfigCache) -> WebDriverResult<Vec<Self>> {
+        let mode = ddg_caps.privacy_config_validation.unwrap_or_default();
+        let mut configs = Vec::new();
+        if let Some(config) = Self::privacy_config(ddg_caps, cache)? {
+            configs.push(config);
+        }
+        for (kind, url) in [
+            (ConfigKind::TrackerDataSet, &ddg_caps.tracker_data_url),
+            (ConfigKind::Surrogates, &ddg_caps.surrogates_url),
+        ] {
+            if let Some(url) = url {
+                configs.push(RemoteConfig::fetched(kind, url, cache));
+            }
+        }
+        for config in &configs {
+            config.check(mode)?;
+        }
+        Ok(configs)
+    }
+
+    /// Resolve `ddg:privacyConfig` or `ddg:privacyConfigURL`, with
+    /// `ddg:privacyConfigPatch` applied
+    fn privacy_config(ddg_caps: &DdgCapabilities, cache: &ConfigCache) -> WebDriverResult<Option<Self>> {
+        let config = if let Some(ref inline) = ddg_caps.privacy_config {
+            RemoteConfig {
+                kind: ConfigKind::PrivacyConfiguration,
+                data: serde_json::to_vec(inline).map_err(|e| format!("Failed to serialize inline privacy config: {}", e)),
+                fallback_url: None,
+            }
+        } else if let Some(ref config_url) = ddg_caps.privacy_config_url {
+            RemoteConfig::fetched(ConfigKind::PrivacyConfiguration, config_url, cache)
+        } else {
+            return Ok(None);
+        };
+        let Some(ref patch) = ddg_caps.privacy_config_patch else {
+            return Ok(Some(config));
+        };
+        let base = config.data.map_err(|e| {
+            WebDriverError::new(
+                ErrorStatus::SessionNotCreated,
+                format!("Failed to fetch the privacy config to patch: {}", e),
+            )
+        })?;
+        let mut base: Value = serde_json::from_slice(&base)
+            .map_err(|e| invalid_argument(format!("Privacy config to patch is not valid JSON: {}", e)))?;
+        apply_patch(&mut base, patch)?;
+        Ok(Some(RemoteConfig {
+            kind: ConfigKind::PrivacyConfiguration,
+            data: Ok(serde_json::to_vec(&base).expect("Failed to serialize patched privacy config")),
+            // The app would download the unpatched config
+            fallback_url: None,
+        }))
+    }
+
+    fn fetched(kind: ConfigKind, url: &str, cache: &ConfigCache) -> Self {
+        info!("Fetching {} from: {}", kind.label(), url);
+        RemoteConfig {
+            kind,
+            data: cache.fetch(url),
+            fallback_url: Some(url.to_string()),
+        }
+    }
+
+    /// Make sure the config was fetched and has the shape the apps expect
+    fn check(&self, mode: ValidationMode) -> WebDriverResult<()> {
+        let label = self.kind.label();
+        let problems = match self.data {
+            Ok(ref data) => content_problems(self.kind, data),
+            Err(ref e) => {
+                if mode == ValidationMode::Strict {
+                    return Err(WebDriverError::new(
+                        ErrorStatus::SessionNotCreated,
+                        format!("Failed to fetch the {}: {}", label, e),
+                    ));
+                }
+                warn!("Failed to fetch the {}, the app will be pointed at its URL: {}", label, e);
+                return Ok(());
+            }
+        };
+        if problems.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!("{} is invalid: {}", label, problems.join("; "));
+        message[..1].make_ascii_uppercase();
+        match mode {
+            ValidationMode::Strict => Err(invalid_argument(message)),
+            ValidationMode::Lenient => {
+                warn!("{}, installing it anyway", message);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn kind(&self) -> ConfigKind {
+        self.kind
+    }
+
+    /// Bytes to write into the app's config cache
+    pub fn data(&self) -> Result<Vec<u8>, String> {
+        self.data.clone()
+    }
+
+    /// URL the app can download the config from itself if writing the cache fails
+    pub fn fallback_url(&self) -> Option<&str> {
+        self.fallback_url.as_deref()
+    }
+}
+
+/// How a config ended up in the app
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InstallMode {
+    /// Nothing was installed; the app uses the config it ships with
+    Bundled,
+    /// Written into the app's config cache with an etag
+    CacheWrite,
+    /// The app was pointed at the config URL and downloads it itself
+    Url,
+    /// Passed as a file through `TEST_PRIVACY_CONFIG_PATH`
+    EnvVar,
+    /// Writing the cache failed and there was no URL to fall back to
+    NotInstalled,
+}
+
+/// Privacy config the driver installed into a session's app, as reported
+/// by the privacy config extension command
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledConfig {
+    pub install_mode: InstallMode,
+    /// `version` of the installed config
+    pub version: Option<Value>,
+    /// Content hash of the config the app was given, unless it downloads it itself
+    pub content_hash: Option<String>,
+    /// Etag written next to the cached copy
+    pub etag: Option<String>,
+    /// URL or file the config came from; `None` for inline and patched configs
+    pub source: Option<String>,
+}
+
+impl InstalledConfig {
+    /// The app's bundled config, when a session didn't ask for one
+    pub fn bundled() -> Self {
+        InstalledConfig {
+            install_mode: InstallMode::Bundled,
+            version: None,
+            content_hash: None,
+            etag: None,
+            source: None,
+        }
+    }
+
+    /// A config installed through the cache write or URL fallback
+    pub fn new(config: &RemoteConfig, install_mode: InstallMode) -> Self {
+        let data = config.data.as_ref().ok().filter(|_| install_mode == InstallMode::CacheWrite);
+        InstalledConfig {
+            install_mode,
+            version: data.and_then(|data| config_version(data)),
+            content_hash: data.map(|data| content_hash(data)),
+            etag: data.map(|data| content_etag(data)),
+            source: config.fallback_url.clone(),
+        }
+    }
+
+    /// A `ddg:privacyConfigPath` file passed through `TEST_PRIVACY_CONFIG_PATH`
+    pub fn from_path(config_path: &str) -> Self {
+        let data = std::fs::read(config_path).ok();
+        InstalledConfig {
+            install_mode: InstallMode::EnvVar,
+            version: data.as_deref().and_then(config_version),
+            content_hash: data.as_deref().map(content_hash),
+            etag: None,
+            source: Some(config_path.to_string()),
+        }
+    }
+}
+
+/// `version` of a privacy config
+fn config_version(data: &[u8]) -> Option<Value> {
+    serde_json::from_slice::<Value>(data).ok()?.get("version").cloned()
+}
+
+/// How a fetched config differs from what the apps expect of its kind
+fn content_problems(kind: ConfigKind, data: &[u8]) -> Vec<String> {
+    if kind == ConfigKind::Surrogates {
+        // Surrogates are a text file of scripts rather than JSON
+        return match std::str::from_utf8(data) {
+            Ok(_) => Vec::new(),
+            Err(e) => vec![format!("not UTF-8 text ({})", e)],
+        };
+    }
+    match serde_json::from_slice::<Value>(data) {
+        Ok(config) if kind == ConfigKind::TrackerDataSet => tracker_data_problems(&config),
+        Ok(config) => shape_problems(&config),
+        Err(e) => vec![format!("not valid JSON ({})", e)],
+    }
+}
+
+/// Apply a `ddg:privacyConfigPatch`: an RFC 6902 list of operations, or an
+/// RFC 7396 merge patch object
+fn apply_patch(config: &mut Value, patch: &Value) -> WebDriverResult<()> {
+    match patch {
+        Value::Array(_) => {
+            let operations: json_patch::Patch = serde_json::from_value(patch.clone())
+                .map_err(|e| invalid_argument(format!("Invalid ddg:privacyConfigPatch: {}", e)))?;
+            json_patch::patch(config, &operations)
+                .map_err(|e| invalid_argument(format!("Failed to apply ddg:privacyConfigPatch: {}", e)))
+        }
+        _ => {
+            json_patch::merge(config, patch);
+            Ok(())
+        }
+    }
+}
+
+/// How `config` differs from the privacy config schema: a `features` object
+/// whose entries have a `state` and optional `exceptions`, an
+/// `unprotectedTemporary` list and an optional `version`
+fn shape_problems(config: &Value) -> Vec<String> {
+    let Some(config) = config.as_object() else {
+        return vec!["not a JSON object".to_string()];
+    };
+    let mut problems = Vec::new();
+    match config.get("features") {
+        Some(Value::Object(features)) => {
+            for (name, feature) in features {
+                let Some(feature) = feature.as_object() else {
+                    problems.push(format!("features.{} is not an object", name));
+                    continue;
+                };
+                if !feature.get("state").is_some_and(Value::is_string) {
+                    problems.push(format!("features.{}.state is missing or not a string", name));
+                }
+                if let Some(exceptions) = feature.get("exceptions") {
+                    problems.extend(domain_list_problems(&format!("features.{}.exceptions", name), exceptions));
+                }
+            }
+        }
+        Some(_) => problems.push("features is not an object".to_string()),
+        None => problems.push("features is missing".to_string()),
+    }
+    match config.get("unprotectedTemporary") {
+        Some(unprotected) => problems.extend(domain_list_problems("unprotectedTemporary", unprotected)),
+        None => problems.push("unprotectedTemporary is missing".to_string()),
+    }
+    if let Some(version) = config.get("version") {
+        if !version.is_number() {
+            problems.push("version is not a number".to_string());
+        }
+    }
+    problems
+}
+
+/// How `tds` differs from the tracker data set schema: `trackers`,
+/// `entities` and `domains` objects
+fn tracker_data_problems(tds: &Value) -> Vec<String> {
+    let Some(tds) = tds.as_object() else {
+        return vec!["not a JSON object".to_string()];
+    };
+    ["trackers", "entities", "domains"]
+        .into_iter()
+        .filter_map(|key| match tds.get(key) {
+            Some(Value::Object(_)) => None,
+            Some(_) => Some(format!("{} is not an object", key)),
+            None => Some(format!("{} is missing", key)),
+        })
+        .collect()
+}
+
+/// Problems with a list of `{ "domain": ..., "reason": ... }` entries
+fn domain_list_problems(path: &str, list: &Value) -> Vec<String> {
+    let Some(entries) = list.as_array() else {
+        return vec![format!("{} is not an array", path)];
+    };
+    entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| !entry.get("domain").is_some_and(Value::is_string))
+        .map(|(index, _)| format!("{}[{}].domain is missing or not a string", path, index))
+        .collect()
+}
+
+fn invalid_argument(message: String) -> WebDriverError {
+    WebDriverError::new(ErrorStatus::InvalidArgument, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Offline, so tests never reach the network
+    fn cache() -> ConfigCache {
+        ConfigCache::new(std::env::temp_dir().join("ddgdriver-remote-config-tests"), true)
+    }
+
+    #[test]
+    fn inline_config_has_no_fallback_url() {
+        let ddg_caps = DdgCapabilities {
+            privacy_config: Some(json!({ "version": 1, "features": {}, "unprotectedTemporary": [] })),
+            ..Default::default()
+        };
+        let configs = RemoteConfig::from_capabilities(&ddg_caps, &cache()).unwrap();
+        assert_eq!(configs.len(), 1);
+        assert_eq!(configs[0].kind(), ConfigKind::PrivacyConfiguration);
+        assert_eq!(configs[0].fallback_url(), None);
+        let data: Value = serde_json::from_slice(&configs[0].data().unwrap()).unwrap();
+        assert_eq!(data["version"], 1);
+        assert_eq!(RemoteConfig::from_capabilities(&DdgCapabilities::default(), &cache()).unwrap(), vec![]);
+    }
+
+    fn patched(patch: Value) -> WebDriverResult<Value> {
+        let ddg_caps = DdgCapabilities {
+            privacy_config: Some(json!({
+                "version": 1,
+                "features": { "nintendo": { "state": "enabled", "exceptions": [] } },
+                "unprotectedTemporary": [],
+            })),
+            privacy_config_patch: Some(patch),
+            ..Default::default()
+        };
+        let configs = RemoteConfig::from_capabilities(&ddg_caps, &cache())?;
+        Ok(serde_json::from_slice(&configs[0].data().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn applies_merge_patches() {
+        let config = patched(json!({ "features": { "nintendo": { "state": "disabled" } } })).unwrap();
+        assert_eq!(config["features"]["nintendo"], json!({ "state": "disabled", "exceptions": [] }));
+        assert_eq!(config["version"], 1);
+    }
+
+    #[test]
+    fn applies_json_patch_operations() {
+        let config = patched(json!([
+            { "op": "add", "path": "/unprotectedTemporary/-", "value": { "domain": "example.com", "reason": "" } },
+            { "op": "remove", "path": "/features/nintendo" },
+        ]))
+        .unwrap();
+        assert_eq!(config["unprotectedTemporary"][0]["domain"], "example.com");
+        assert_eq!(config["features"], json!({}));
+    }
+
+    fn checked(config: Value, mode: ValidationMode) -> WebDriverResult<()> {
+        let ddg_caps = DdgCapabilities {
+            privacy_config: Some(config),
+            privacy_config_validation: Some(mode),
+            ..Default::default()
+        };
+        RemoteConfig::from_capabilities(&ddg_caps, &cache()).map(|_| ())
+    }
+
+    #[test]
+    fn checks_the_config_shape() {
+        let valid = json!({
+            "version": 1,
+            "features": { "contentBlocking": { "state": "enabled", "exceptions": [{ "domain": "example.com" }] } },
+            "unprotectedTemporary": [],
+        });
+        assert!(checked(valid, ValidationMode::Strict).is_ok());
+
+        let invalid = json!({
+            "features": { "contentBlocking": { "exceptions": [{ "reason": "no domain" }] } },
+            "version": [],
+        });
+        let err = checked(invalid.clone(), ValidationMode::Strict).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        assert_eq!(
+            err.message,
+            "Privacy config is invalid: features.contentBlocking.state is missing or not a string; \
+             features.contentBlocking.exceptions[0].domain is missing or not a string; \
+             unprotectedTemporary is missing; version is not a number"
+        );
+        assert!(checked(invalid, ValidationMode::Lenient).is_ok());
+
+        let string_version = json!({ "version": "1", "features": {}, "unprotectedTemporary": [] });
+        let err = checked(string_version, ValidationMode::Strict).unwrap_err();
+        assert_eq!(err.message, "Privacy config is invalid: version is not a number");
+    }
+
+    #[test]
+    fn strict_mode_rejects_configs_that_are_not_json() {
+        let config = RemoteConfig {
+            kind: ConfigKind::PrivacyConfiguration,
+            data: Ok(b"<html>502 Bad Gateway</html>".to_vec()),
+            fallback_url: Some("https://example.com/config.json".to_string()),
+        };
+        assert_eq!(config.check(ValidationMode::Strict).unwrap_err().error, ErrorStatus::InvalidArgument);
+        assert!(config.check(ValidationMode::Lenient).is_ok());
+
+        let unreachable = RemoteConfig {
+            kind: ConfigKind::PrivacyConfiguration,
+            data: Err("HTTP error: 404 Not Found".to_string()),
+            fallback_url: Some("https://example.com/config.json".to_string()),
+        };
+        assert_eq!(unreachable.check(ValidationMode::Strict).unwrap_err().error, ErrorStatus::SessionNotCreated);
+        assert!(unreachable.check(ValidationMode::Lenient).is_ok());
+    }
+
+    #[test]
+    fn failed_patches_are_invalid_argument() {
+        let err = patched(json!([{ "op": "replace", "path": "/features/missing/state", "value": "disabled" }])).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        let err = patched(json!([{ "op": "test", "path": "/version", "value": 2 }])).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn reports_what_was_installed() {
+        let ddg_caps = DdgCapabilities {
+            privacy_config: Some(json!({ "version": 42, "features": {}, "unprotectedTemporary": [] })),
+            ..Default::default()
+        };
+        let config = RemoteConfig::from_capabilities(&ddg_caps, &cache()).unwrap().remove(0);
+        let installed = InstalledConfig::new(&config, InstallMode::CacheWrite);
+        assert_eq!(installed.version, Some(json!(42)));
+        assert_eq!(installed.content_hash, Some(content_hash(&config.data().unwrap())));
+        assert_eq!(installed.etag, Some(content_etag(&config.data().unwrap())));
+        assert_eq!(installed.source, None);
+
+        let not_installed = InstalledConfig::new(&config, InstallMode::NotInstalled);
+        assert_eq!((not_installed.version, not_installed.content_hash), (None, None));
+        assert_eq!(serde_json::to_value(InstalledConfig::bundled()).unwrap()["installMode"], "bundled");
+    }
+
+    #[test]
+    fn fetches_tracker_data_and_surrogates() {
+        let dir = std::env::temp_dir().join(format!("ddgdriver-remote-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tds = dir.join("tds.json");
+        let surrogates = dir.join("surrogates.txt");
+        std::fs::write(&tds, r#"{ "trackers": {}, "entities": {}, "domains": {} }"#).unwrap();
+        std::fs::write(&surrogates, "example.com/tracker.js application/javascript\n(function() {})();\n").unwrap();
+        let ddg_caps = DdgCapabilities {
+            tracker_data_url: Some(format!("file://{}", tds.display())),
+            surrogates_url: Some(format!("file://{}", surrogates.display())),
+            ..Default::default()
+        };
+        let configs = RemoteConfig::from_capabilities(&ddg_caps, &cache()).unwrap();
+        let kinds: Vec<ConfigKind> = configs.iter().map(RemoteConfig::kind).collect();
+        assert_eq!(kinds, [ConfigKind::TrackerDataSet, ConfigKind::Surrogates]);
+        assert_eq!(configs[1].fallback_url(), ddg_caps.surrogates_url.as_deref());
+
+        std::fs::write(&tds, r#"{ "trackers": [], "entities": {} }"#).unwrap();
+        let err = RemoteConfig::from_capabilities(&ddg_caps, &cache()).unwrap_err();
+        assert_eq!(err.error, ErrorStatus::InvalidArgument);
+        assert_eq!(err.message, "Tracker data set is invalid: trackers is not an object; domains is missing");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
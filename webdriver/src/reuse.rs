@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Child;
+use std::time::Duration;
+
+use crate::automation::{make_server_request, parse_message, AutomationError, DEFAULT_REQUEST_TIMEOUT};
+use crate::capabilities::DdgCapabilities;
+use crate::handler::{DuckDuckGoClearDataParameters, Platform};
+use crate::remote_config::InstalledConfig;
+
+/// How long the app may take to clear browsing data
+const CLEAR_DATA_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// App a `ddg:reuseApp` session left running for the next one to attach to.
+/// Its port and simulator stay reserved until a session takes it over.
+pub struct KeptApp {
+    pub platform: Platform,
+    pub port: u16,
+    pub simulator_udid: Option<String>,
+    /// Device type and runtime of the simulator, for iOS apps
+    pub device: Option<(String, String)>,
+    pub bundle_id: String,
+    pub app_path: String,
+    /// `content_hash` of the bundle the app was installed or launched from
+    pub app_hash: String,
+    /// Capabilities the app was set up with
+    pub ddg_caps: DdgCapabilities,
+    /// Privacy config the app was given
+    pub privacy_config: InstalledConfig,
+    pub extracted_app: Option<PathBuf>,
+    pub log_stream: Option<Child>,
+}
+
+impl KeptApp {
+    /// Whether a session could take this app over as it is, without
+    /// reinstalling or relaunching it
+    pub fn is_attachable(&self, app_hash: &str, ddg_caps: &DdgCapabilities) -> bool {
+        // Remote configs are only installed at launch
+        self.app_hash == app_hash
+            && self.ddg_caps.privacy_config_url == ddg_caps.privacy_config_url
+            && self.ddg_caps.privacy_config == ddg_caps.privacy_config
+            && self.ddg_caps.privacy_config_patch == ddg_caps.privacy_config_patch
+            && self.ddg_caps.privacy_config_path == ddg_caps.privacy_config_path
+            && self.ddg_caps.tracker_data_url == ddg_caps.tracker_data_url
+            && self.ddg_caps.surrogates_url == ddg_caps.surrogates_url
+            && self.ddg_caps.feature_flags == ddg_caps.feature_flags
+    }
+}
+
+/// Put a running app back into a fresh state for the next session: a single
+/// blank tab and no browsing data
+pub fn reset_app_state(port: u16) -> Result<(), AutomationError> {
+    let timeout = Some(DEFAULT_REQUEST_TIMEOUT);
+    let handles = make_server_request(port, "getWindowHandles", &HashMap::new(), timeout)?;
+    let handles: Vec<String> = parse_message("getWindowHandles", &handles)?;
+    info!("Resetting app on port {}: closing {} extra tab(s)", port, handles.len().saturating_sub(1));
+    for handle in handles.iter().skip(1) {
+        make_server_request(port, "switchToWindow", &HashMap::from([("handle", handle.as_str())]), timeout)?;
+        make_server_request(port, "closeWindow", &HashMap::new(), timeout)?;
+    }
+    if let Some(first) = handles.first() {
+        make_server_request(port, "switchToWindow", &HashMap::from([("handle", first.as_str())]), timeout)?;
+    }
+    make_server_request(port, "navigate", &HashMap::from([("url", "about:blank")]), timeout)?;
+    // Older app builds can't clear data over automation; their tabs are still reset
+    if let Err(e) = clear_browsing_data(port, &DuckDuckGoClearDataParameters::default()) {
+        warn!("Failed to clear browsing data on port {}: {}", port, e);
+    }
+    Ok(())
+}
+
+/// Run the app's Fire / clear browsing data flow. The automation server
+/// answers once the data is gone, which can take a while with many tabs.
+pub fn clear_browsing_data(port: u16, parameters: &DuckDuckGoClearDataParameters) -> Result<(), AutomationError> {
+    let scope = serde_json::to_value(parameters.scope).expect("Failed to serialize clear data scope");
+    let keep_fireproofed = parameters.keep_fireproofed.to_string();
+    let params = HashMap::from([
+        ("scope", scope.as_str().unwrap_or_default()),
+        ("keepFireproofed", keep_fireproofed.as_str()),
+    ]);
+    info!("Clearing browsing data on port {}: {:?}", port, parameters);
+    make_server_request(port, "clearBrowsingData", &params, Some(CLEAR_DATA_TIMEOUT))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attaches_only_to_the_same_bundle_and_remote_configs() {
+        let kept = KeptApp {
+            platform: Platform::MacOS,
+            port: 8557,
+            simulator_udid: None,
+            device: None,
+            bundle_id: "com.duckduckgo.macos.browser.debug".to_string(),
+            app_path: "/apps/DuckDuckGo.app".to_string(),
+            app_hash: "abc".to_string(),
+            ddg_caps: DdgCapabilities::default(),
+            privacy_config: InstalledConfig::bundled(),
+            extracted_app: None,
+            log_stream: None,
+        };
+        assert!(kept.is_attachable("abc", &DdgCapabilities::default()));
+        assert!(!kept.is_attachable("def", &DdgCapabilities::default()));
+        let other_config = DdgCapabilities {
+            privacy_config_url: Some("https://example.com/config.json".to_string()),
+            ..Default::default()
+        };
+        assert!(!kept.is_attachable("abc", &other_config));
+        let other_tracker_data = DdgCapabilities {
+            tracker_data_url: Some("https://example.com/tds.json".to_string()),
+            ..Default::default()
+        };
+        assert!(!kept.is_attachable("abc", &other_tracker_data));
+    }
+}
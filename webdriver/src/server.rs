@@ -0,0 +1,248 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::oneshot;
+
+use url::{Host, Url};
+use warp::http::{header, Method, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Filter;
+use webdriver::command::WebDriverMessage;
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+use webdriver::httpapi::{standard_routes, Route};
+use webdriver::Parameters;
+
+use crate::handler::{DuckDuckGoExtensionRoute, Handler};
+
+/// Running HTTP server; dropping it waits for the server thread to finish
+pub struct Listener {
+    guard: Option<thread::JoinHandle<()>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    pub socket: SocketAddr,
+}
+
+impl Listener {
+    /// Stop accepting connections and wait for in-flight requests to finish
+    pub fn stop(mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = self.guard.take().map(|j| j.join());
+    }
+}
+
+/// Serve the WebDriver protocol on `address`.
+///
+/// Unlike `webdriver::server::start`, which hands every command to a single
+/// dispatcher thread, each request runs on its own blocking thread, so
+/// commands for different sessions do not wait for each other.
+pub fn start(
+    address: SocketAddr,
+    allow_hosts: Vec<Host>,
+    allow_origins: Vec<Url>,
+    handler: Arc<Handler>,
+    extension_routes: Vec<(Method, &'static str, DuckDuckGoExtensionRoute)>,
+) -> std::io::Result<Listener> {
+    let mut routes = standard_routes::<DuckDuckGoExtensionRoute>();
+    for (method, path, route) in extension_routes {
+        routes.push((method, path, Route::Extension(route)));
+    }
+    let server = Arc::new(Server {
+        address,
+        allow_hosts,
+        allow_origins,
+        routes,
+        handler,
+    });
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    let filter = warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .then(move |method: Method, path: warp::path::FullPath, host: Option<String>, origin: Option<String>, content_type: Option<String>, body: Bytes| {
+            let server = server.clone();
+            async move {
+                let path = path.as_str().to_string();
+                let headers = RequestHeaders { host, origin, content_type };
+                let (status, body) = tokio::task::spawn_blocking(move || server.respond(method, &path, headers, &body))
+                    .await
+                    .unwrap_or_else(|e| {
+                        let err = WebDriverError::new(ErrorStatus::UnknownError, format!("Command panicked: {}", e));
+                        (err.http_status(), serde_json::to_string(&err).unwrap())
+                    });
+                warp::reply::with_status(body, status)
+            }
+        })
+        .with(warp::reply::with::header(header::CONTENT_TYPE, "application/json; charset=utf-8"))
+        .with(warp::reply::with::header(header::CACHE_CONTROL, "no-cache"));
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    let (socket, serve) = {
+        let _runtime = rt.enter();
+        warp::serve(filter)
+            .try_bind_with_graceful_shutdown(address, async {
+                let _ = stopped.await;
+            })
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::AddrInUse, e))?
+    };
+
+    let guard = thread::Builder::new()
+        .name("webdriver server".to_string())
+        .spawn(move || rt.block_on(serve))?;
+
+    Ok(Listener {
+        guard: Some(guard),
+        shutdown: Some(shutdown),
+        socket,
+    })
+}
+
+/// Request headers checked before a command runs
+struct RequestHeaders {
+    host: Option<String>,
+    origin: Option<String>,
+    content_type: Option<String>,
+}
+
+struct Server {
+    address: SocketAddr,
+    allow_hosts: Vec<Host>,
+    allow_origins: Vec<Url>,
+    routes: Vec<(Method, &'static str, Route<DuckDuckGoExtensionRoute>)>,
+    handler: Arc<Handler>,
+}
+
+impl Server {
+    fn respond(&self, method: Method, path: &str, headers: RequestHeaders, body: &Bytes) -> (StatusCode, String) {
+        if method == Method::HEAD {
+            return (StatusCode::OK, String::new());
+        }
+        debug!("-> {} {} {}", method, path, String::from_utf8_lossy(body));
+        let result = self
+            .check_request(&method, headers)
+            .and_then(|_| self.message(&method, path, body))
+            .and_then(|msg| self.handler.handle_command(msg));
+        let (status, body) = match result {
+            Ok(response) => (StatusCode::OK, serde_json::to_string(&response).unwrap()),
+            Err(e) => (e.http_status(), serde_json::to_string(&e).unwrap()),
+        };
+        debug!("<- {} {}", status, body);
+        (status, body)
+    }
+
+    /// Only accept requests addressed to this server from an allowed origin,
+    /// so web pages cannot drive the browser
+    fn check_request(&self, method: &Method, headers: RequestHeaders) -> WebDriverResult<()> {
+        let RequestHeaders { host, origin, content_type } = headers;
+        let host = host.ok_or_else(|| WebDriverError::new(ErrorStatus::UnknownError, "Missing Host header"))?;
+        if !is_host_allowed(&self.address, &self.allow_hosts, &host) {
+            warn!("Rejected request with Host header {}", host);
+            return Err(WebDriverError::new(ErrorStatus::UnknownError, format!("Invalid Host header {}", host)));
+        }
+        if let Some(origin) = origin {
+            let allowed = Url::parse(&origin).is_ok_and(|url| self.allow_origins.contains(&url));
+            if !allowed {
+                warn!("Rejected request with Origin header {}", origin);
+                return Err(WebDriverError::new(ErrorStatus::UnknownError, format!("Invalid Origin header {}", origin)));
+            }
+        }
+        // Content types a page can POST without a CORS preflight
+        if *method == Method::POST {
+            let content_type = content_type
+                .as_deref()
+                .map(|value| value.split(';').next().unwrap_or("").trim().to_lowercase());
+            if let Some("application/x-www-form-urlencoded" | "multipart/form-data" | "text/plain") = content_type.as_deref() {
+                warn!("Rejected POST request with disallowed content type {:?}", content_type);
+                return Err(WebDriverError::new(ErrorStatus::UnknownError, "Invalid Content-Type"));
+            }
+        }
+        Ok(())
+    }
+
+    fn message(&self, method: &Method, path: &str, body: &Bytes) -> WebDriverResult<WebDriverMessage<DuckDuckGoExtensionRoute>> {
+        let mut path_matched = false;
+        for (route_method, template, route) in &self.routes {
+            let Some(params) = match_path(template, path) else {
+                continue;
+            };
+            path_matched = true;
+            if route_method != method {
+                continue;
+            }
+            let body = std::str::from_utf8(body)
+                .map_err(|_| WebDriverError::new(ErrorStatus::UnknownError, "Request body wasn't valid UTF-8"))?;
+            return WebDriverMessage::from_http(route.clone(), &params, body, *method == Method::POST);
+        }
+        if path_matched {
+            Err(WebDriverError::new(ErrorStatus::UnknownMethod, format!("{} {} did not match a known command", method, path)))
+        } else {
+            Err(WebDriverError::new(ErrorStatus::UnknownCommand, format!("{} {} did not match a known command", method, path)))
+        }
+    }
+}
+
+fn is_host_allowed(server_address: &SocketAddr, allow_hosts: &[Host], host_header: &str) -> bool {
+    let Ok(url) = Url::parse(&format!("http://{}", host_header)) else {
+        return false;
+    };
+    let host_matches = match url.host() {
+        Some(Host::Domain(domain)) => allow_hosts.contains(&Host::Domain(domain.to_string())),
+        Some(Host::Ipv4(_)) | Some(Host::Ipv6(_)) => true,
+        None => false,
+    };
+    host_matches && url.port_or_known_default() == Some(server_address.port())
+}
+
+/// Match a route template like `/session/{sessionId}/url` against a request
+/// path, returning the template's parameters
+fn match_path(template: &str, path: &str) -> Option<Parameters> {
+    let mut params = Parameters::new();
+    let mut template_parts = template.split('/').filter(|part| !part.is_empty());
+    let mut path_parts = path.split('/').filter(|part| !part.is_empty());
+    loop {
+        match (template_parts.next(), path_parts.next()) {
+            (None, None) => return Some(params),
+            (Some(name), Some(value)) if name.starts_with('{') && name.ends_with('}') => {
+                let value = urlencoding::decode(value).ok()?;
+                params.insert(name[1..name.len() - 1].to_string(), value.into_owned());
+            }
+            (Some(expected), Some(actual)) if expected == actual => {}
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_templates_with_parameters() {
+        let params = match_path("/session/{sessionId}/element/{elementId}/click", "/session/abc/element/e%201/click").unwrap();
+        assert_eq!(params["sessionId"], "abc");
+        assert_eq!(params["elementId"], "e 1");
+        assert!(match_path("/session/{sessionId}/url", "/session/abc/title").is_none());
+        assert!(match_path("/session/{sessionId}", "/session/abc/url").is_none());
+        assert!(match_path("/status", "/status/").is_some());
+    }
+
+    #[test]
+    fn checks_host_and_port() {
+        let address: SocketAddr = "127.0.0.1:4444".parse().unwrap();
+        let localhost = vec![Host::Domain("localhost".to_string())];
+        assert!(is_host_allowed(&address, &localhost, "localhost:4444"));
+        assert!(is_host_allowed(&address, &localhost, "127.0.0.1:4444"));
+        assert!(!is_host_allowed(&address, &localhost, "localhost:4445"));
+        assert!(!is_host_allowed(&address, &localhost, "example.com:4444"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Child;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{Map, Value};
+use webdriver::command::TimeoutsParameters;
+use webdriver::error::{ErrorStatus, WebDriverError, WebDriverResult};
+
+use crate::automation::{make_server_request, DEFAULT_REQUEST_TIMEOUT};
+use crate::capabilities::DdgCapabilities;
+use crate::handler::{DuckDuckGoContext, Platform};
+use crate::remote_config::InstalledConfig;
+
+/// State the driver keeps for one live session
+pub struct DriverSession {
+    pub platform: Platform,
+    /// Port the app's automation server listens on
+    pub port: u16,
+    /// Simulator the app runs in, for iOS sessions
+    pub simulator_udid: Option<String>,
+    /// Device type and runtime of the simulator, for iOS sessions
+    pub device: Option<(String, String)>,
+    /// Bundle identifier of the app under test
+    pub bundle_id: String,
+    /// Path of the `.app` bundle that was installed or launched
+    pub app_path: String,
+    /// Directory a zipped `ddg:app` was extracted to, removed when the session ends
+    pub extracted_app: Option<PathBuf>,
+    /// `ddg:*` capabilities the session was started with, with the privacy
+    /// config entries of any later config change
+    pub ddg_caps: Mutex<DdgCapabilities>,
+    /// Privacy config the app was given at launch or by the last config change
+    pub privacy_config: Mutex<InstalledConfig>,
+    /// Whether element commands target the page or the native browser UI
+    pub context: Mutex<DuckDuckGoContext>,
+    /// Content hash of the app bundle for `ddg:reuseApp` sessions, whose app
+    /// is kept running when the session ends
+    pub app_hash: Option<String>,
+    /// `log stream` child forwarding the app's logs for the session's lifetime
+    pub log_stream: Mutex<Option<Child>>,
+    /// Capabilities reported back to the client in the NewSession response
+    pub capabilities: Map<String, Value>,
+    pub timeouts: Mutex<Timeouts>,
+    /// When a command for the session last started or finished
+    pub last_activity: Mutex<Instant>,
+    /// Commands for the session that are still running
+    pub active_commands: AtomicUsize,
+}
+
+impl DriverSession {
+    /// Send `method` to this session's automation server
+    pub fn request(&self, method: &str, params: &HashMap<&str, &str>, timeout: Option<Duration>) -> WebDriverResult<String> {
+        Ok(make_server_request(self.port, method, params, timeout)?)
+    }
+
+    pub fn context(&self) -> DuckDuckGoContext {
+        *self.context.lock().unwrap()
+    }
+
+    /// Fail commands that only make sense for web content, like scripts,
+    /// while the session targets the native UI
+    pub fn require_content_context(&self) -> WebDriverResult<()> {
+        if self.context() == DuckDuckGoContext::Chrome {
+            return Err(WebDriverError::new(
+                ErrorStatus::UnsupportedOperation,
+                "Scripts can only run in the content context",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Snapshot of the session's current timeouts
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts.lock().unwrap().clone()
+    }
+
+    /// Mark a command as running until the returned guard is dropped
+    pub fn begin_command(self: &Arc<Self>) -> CommandGuard {
+        self.active_commands.fetch_add(1, Ordering::SeqCst);
+        *self.last_activity.lock().unwrap() = Instant::now();
+        CommandGuard { session: self.clone() }
+    }
+
+    /// How long the session has gone without commands; `None` while one is running
+    pub fn idle_for(&self) -> Option<Duration> {
+        if self.active_commands.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(self.last_activity.lock().unwrap().elapsed())
+    }
+}
+
+/// A running command; the session counts as active until it is dropped
+pub struct CommandGuard {
+    session: Arc<DriverSession>,
+}
+
+impl Drop for CommandGuard {
+    fn drop(&mut self) {
+        *self.session.last_activity.lock().unwrap() = Instant::now();
+        self.session.active_commands.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Extra time the transport waits beyond a command's own budget, so the app
+/// gets the chance to report the failure itself
+const TRANSPORT_GRACE: Duration = Duration::from_secs(5);
+
+/// Session timeouts as defined by the WebDriver spec, in milliseconds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// `None` means scripts are never interrupted
+    pub script: Option<u64>,
+    pub page_load: u64,
+    pub implicit: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            script: Some(30_000),
+            page_load: 300_000,
+            implicit: 0,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn update(&mut self, params: &TimeoutsParameters) {
+        if let Some(script) = params.script {
+            self.script = script;
+        }
+        if let Some(page_load) = params.page_load {
+            self.page_load = page_load;
+        }
+        if let Some(implicit) = params.implicit {
+            self.implicit = implicit;
+        }
+    }
+
+    /// The timeouts as reported in capabilities and by `GET /timeouts`
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "script": self.script,
+            "pageLoad": self.page_load,
+            "implicit": self.implicit,
+        })
+    }
+
+    /// Transport deadline for a script execution
+    pub fn script_deadline(&self) -> Option<Duration> {
+        self.script.map(|ms| Duration::from_millis(ms) + TRANSPORT_GRACE)
+    }
+
+    /// Transport deadline for a navigation
+    pub fn page_load_deadline(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.page_load) + TRANSPORT_GRACE)
+    }
+
+    /// Transport deadline for an element lookup, which first waits for the
+    /// document to load and then polls for up to the implicit wait
+    pub fn element_lookup_deadline(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.implicit) + DEFAULT_REQUEST_TIMEOUT)
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use webdriver::error::WebDriverResult;
+
+use crate::automation::{parse_message, DEFAULT_REQUEST_TIMEOUT};
+use crate::session::DriverSession;
+
+/// Third-party requests the current tab made, as the app's content blocker
+/// and privacy dashboard saw them
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackerReport {
+    /// Page the requests belong to
+    pub page_url: String,
+    pub requests: Vec<TrackerRequest>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackerRequest {
+    pub url: String,
+    /// Tracker Radar entity owning the request's domain
+    #[serde(default)]
+    pub entity: Option<String>,
+    /// Rule that matched the request, if any
+    #[serde(default)]
+    pub rule: Option<String>,
+    pub state: RequestState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RequestState {
+    Blocked,
+    Allowed,
+    /// Replaced by one of the app's surrogate scripts
+    Surrogated,
+}
+
+/// The app's tracker report for the current tab
+pub fn current_tab(session: &DriverSession) -> WebDriverResult<TrackerReport> {
+    let response = session.request("getTrackerReport", &HashMap::new(), Some(DEFAULT_REQUEST_TIMEOUT))?;
+    Ok(parse_message("getTrackerReport", &response)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tracker_reports() {
+        let report: TrackerReport = parse_message(
+            "getTrackerReport",
+            r#"{
+                "pageUrl": "https://www.publisher-company.site/product.html?p=12",
+                "requests": [
+                    { "url": "https://convert.ad-company.site/convert.js", "entity": "Ad Company", "rule": "convert.ad-company.site/convert.js", "state": "blocked" },
+                    { "url": "https://www.ad-company.site/track.js", "entity": "Ad Company", "rule": "ad-company.site/track.js", "state": "surrogated" },
+                    { "url": "https://cdn.example.com/app.js", "state": "allowed" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let states: Vec<_> = report.requests.iter().map(|request| request.state).collect();
+        assert_eq!(states, [RequestState::Blocked, RequestState::Surrogated, RequestState::Allowed]);
+        assert_eq!(report.requests[2].entity, None);
+
+        let unknown = r#"{ "pageUrl": "https://example.com/", "requests": [{ "url": "https://t.example/", "state": "ignored" }] }"#;
+        assert!(parse_message::<TrackerReport>("getTrackerReport", unknown).is_err());
+    }
+}